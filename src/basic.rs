@@ -1,12 +1,38 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io::{Write, Read, BufReader, BufRead};
+use std::ops::{Range, RangeBounds};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[path = "basic_runtime.rs"]
+mod runtime;
+
+pub use runtime::Value;
+use runtime::{Array, BinaryOp, Builtin, CompareOp, EvalError, ValueType, call_builtin, parse_number, variable_type};
 
 #[derive(Debug)]
 pub enum InterpreterError {
     RuntimeError { line_number: u16, message: String },
     UnknownVariable { name: String },
     NotANumber { value: String },
-    SyntaxError { code: String },
+    /// A string was given for a number variable, or a number for a `$` variable.
+    TypeMismatch { name: String },
+    SyntaxError { code: String, column: usize, message: String },
+    BudgetExceeded { budget: Budget, line_number: u16, steps: u64 },
+    InvalidSnapshot { line: String },
+    InvalidTranscript { line: String },
+    /// `Interpreter::replay` found the program behaving differently from the transcript, first
+    /// on transcript line `line`. `None` stands for the end of the transcript.
+    TranscriptMismatch { line: usize, expected: Option<String>, actual: Option<String> },
+    RenumberFailed { message: String },
+    /// A function or statement can't be registered under this name, because BASIC code could
+    /// never call it.
+    InvalidName { name: String, reason: String },
+    /// `line_number` is `None` when the problem isn't in a line, like the value of a variable.
+    CannotTranspile { line_number: Option<u16>, reason: String },
+    CannotContinue,
     IoError(std::io::Error),
 }
 
@@ -16,197 +42,5251 @@ impl From<std::io::Error> for InterpreterError {
     }
 }
 
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::RuntimeError { line_number, message } => {
+                write!(f, "Runtime error in line {line_number}: {message}")
+            },
+            Self::UnknownVariable { name } => write!(f, "Unknown variable: {name}"),
+            Self::NotANumber { value } => write!(f, "Not a number: {value}"),
+            Self::TypeMismatch { name } => write!(f, "Type mismatch for variable {name}"),
+            Self::SyntaxError { code, column, message } => {
+                write!(f, "Syntax error in column {column}, {message}: {code}")
+            },
+            Self::BudgetExceeded { budget, line_number, steps } => {
+                let limit = match budget {
+                    Budget::Steps => "Step limit",
+                    Budget::Time  => "Time limit",
+                    Budget::Inputs => "INPUT limit",
+                };
+                write!(f, "{limit} exceeded in line {line_number} after {steps} steps")
+            },
+            Self::InvalidSnapshot { line } => write!(f, "Invalid snapshot line: {line}"),
+            Self::InvalidTranscript { line } => write!(f, "Invalid transcript line: {line}"),
+            Self::TranscriptMismatch { line, expected, actual } => {
+                let describe = |entry: &Option<String>| match entry {
+                    Some(entry) => format!("\"{entry}\""),
+                    None => "end of transcript".to_owned(),
+                };
+                write!(f, "Transcript line {line} differs: expected {}, got {}", describe(expected), describe(actual))
+            },
+            Self::RenumberFailed { message } => write!(f, "Cannot renumber: {message}"),
+            Self::InvalidName { name, reason } => write!(f, "Invalid name \"{name}\": {reason}"),
+            Self::CannotTranspile { line_number: Some(line_number), reason } => {
+                write!(f, "Cannot transpile line {line_number}: {reason}")
+            },
+            Self::CannotTranspile { line_number: None, reason } => write!(f, "Cannot transpile: {reason}"),
+            Self::CannotContinue => write!(f, "Cannot continue, no program was stopped"),
+            Self::IoError(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for InterpreterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The execution limit that stopped a program, see `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Steps,
+    Time,
+    Inputs,
+}
+
+/// Execution limits for `Interpreter::run`. `None` means unlimited. The step and INPUT limits also
+/// apply to debugging sessions; the time limit only applies to `run`, since a paused session
+/// shouldn't run out of time while nobody is stepping it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_duration: Option<Duration>,
+    pub max_inputs: Option<u64>,
+}
+
+/// The first line of every snapshot, so future format changes can be told apart.
+const SNAPSHOT_HEADER: &str = "BASIC SNAPSHOT 1";
+
+/// The first line of every transcript written by `Interpreter::run_recorded`.
+const TRANSCRIPT_HEADER: &str = "BASIC TRANSCRIPT 1";
+
+/// How `Interpreter::run` and `Interpreter::cont` ended, unless the program failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    Finished,
+    /// STOP was executed in this line, and `Interpreter::cont` continues after it.
+    Stopped { line_number: u16 },
+}
+
+/// Why `Interpreter::run_for` returned. Unless the program finished or failed, calling `run_for`
+/// again continues where it stopped.
 #[derive(Debug)]
-enum Statement {
-    Print { value: String },
-    Read { var_name: String },
-    Goto { line_number: u16 },
-    If { condition: String, line_number: u16 },
+pub enum RunStatus {
+    Finished,
+    /// INPUT found no line queued with `Interpreter::feed_input`. `run_for` never reads from the
+    /// input reader, so it can't block on it.
+    NeedsInput,
+    /// The given number of steps were executed without the program finishing.
+    BudgetExhausted,
+    /// STOP was executed in this line.
+    Stopped { line_number: u16 },
+    Error(InterpreterError),
 }
 
-pub struct Interpreter<'a, R: Read, W: Write> {
-    code_lines: HashMap<u16, Statement>,
-    variables: HashMap<String, u16>,
-    input: BufReader<R>,
-    output: &'a mut W,
+/// Where `Interpreter::set_trace` writes executed lines, as `[10]`, or as `[10] X = 5` when
+/// `variables` is set and the line changed a variable.
+pub struct Trace<'a> {
+    pub writer: Box<dyn Write + 'a>,
+    pub variables: bool,
 }
 
-impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
-    pub fn new(input: R, output: &'a mut W) -> Self {
-        Self {
-            code_lines: HashMap::new(),
-            variables: HashMap::new(),
-            input: BufReader::new(input),
-            output,
-        }
+/// How often each line ran during the last `Interpreter::run`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Every line of the program in line number order, with how often it was executed.
+    pub line_counts: Vec<(u16, u64)>,
+    /// Loops that were taken at least once, most executed lines first.
+    pub hottest_loops: Vec<LoopProfile>,
+}
+
+/// A loop found while profiling: a GOTO or NEXT on `last_line` jumping back to `first_line`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopProfile {
+    pub first_line: u16,
+    pub last_line: u16,
+    /// How often the jump back was taken.
+    pub iterations: u64,
+    /// How many lines were executed from `first_line` to `last_line` in total. This is only an
+    /// approximation of the loop's own cost: lines that are also part of other loops, such as an
+    /// inner loop's, count for each of them, and so do lines run in that range outside the loop.
+    pub steps_in_range: u64,
+}
+
+/// A syntax error found by `Interpreter::load`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The line of the program text, counting from 1.
+    pub line: usize,
+    /// Where the offending token starts, in characters counting from 1.
+    pub column: usize,
+    /// How many characters the offending token is long, at least 1.
+    pub length: usize,
+    pub message: String,
+    pub code: String,
+}
+
+impl ParseError {
+    /// The error followed by the line of code, with the offending token underlined.
+    pub fn render(&self) -> String {
+        let underline = " ".repeat(self.column.saturating_sub(1)) + &"^".repeat(self.length);
+        format!("{self}\n    {}\n    {underline}", self.code)
     }
+}
 
-    pub fn add(&mut self, code: &str) -> Result<u16, InterpreterError> {
-        let (line_number, statement) = parse_code_line(code)?;
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
 
-        self.code_lines.insert(line_number, statement);
+/// A syntax error within a single line, with the byte range of the offending text.
+#[derive(Debug)]
+struct LineError {
+    span: Range<usize>,
+    message: String,
+}
 
-        Ok(line_number)
+impl LineError {
+    fn new(span: Range<usize>, message: impl Into<String>) -> Self {
+        Self { span, message: message.into() }
     }
 
-    pub fn run(&mut self) -> Result<(), InterpreterError> {
-        let mut line_numbers = self.code_lines.keys().cloned().collect::<Vec<_>>();
-        line_numbers.sort();
+    /// The column and length of the span in characters, for reporting.
+    fn columns(&self, code: &str) -> (usize, usize) {
+        let column = code[..self.span.start].chars().count() + 1;
+        let length = code[self.span.clone()].chars().count().max(1);
+        (column, length)
+    }
 
-        let Some(&(mut current_line_number)) = line_numbers.get(0) else {
-            return Ok(());
-        };
+    fn into_interpreter_error(self, code: &str) -> InterpreterError {
+        let (column, _) = self.columns(code);
+        InterpreterError::SyntaxError { code: code.to_owned(), column, message: self.message }
+    }
 
-        macro_rules! runtime_error {
-            ($($arg:tt)*) => {
-                InterpreterError::RuntimeError {
-                    line_number: current_line_number,
-                    message: format!($($arg)*),
-                }
-            }
+    fn into_parse_error(self, line: usize, code: &str) -> ParseError {
+        let (column, length) = self.columns(code);
+        ParseError { line, column, length, message: self.message, code: code.to_owned() }
+    }
+}
+
+/// Where a debugging session stopped after `Interpreter::step` or `Interpreter::resume`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugStatus {
+    Paused { line_number: u16 },
+    Finished,
+}
+
+/// A problem found by `Interpreter::check` without running the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    MissingJumpTarget { line_number: u16, target: u16 },
+    UninitializedVariable { line_number: u16, name: String },
+    UnreachableLine { line_number: u16 },
+    NeverTerminates { line_number: u16 },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingJumpTarget { line_number, target } => {
+                write!(f, "Line {line_number}: jump to missing line {target}")
+            },
+            Self::UninitializedVariable { line_number, name } => {
+                write!(f, "Line {line_number}: {name} may be used before it is assigned")
+            },
+            Self::UnreachableLine { line_number } => {
+                write!(f, "Line {line_number}: unreachable")
+            },
+            Self::NeverTerminates { line_number } => {
+                write!(f, "Line {line_number}: the program can never finish after this line")
+            },
         }
+    }
+}
 
-        loop {
-            let statement = self.code_lines.get(&current_line_number).unwrap();
-
-            match statement {
-                Statement::Print { value } => {
-                    match self.eval_value(value) {
-                        Ok(number) => writeln!(self.output, "{}", number)?,
-                        Err(InterpreterError::UnknownVariable { name }) => {
-                            return Err(runtime_error!("Unknown variable: {name}"));
-                        },
-                        _ => writeln!(self.output, "{}", value)?,
-                    }
-                },
-                Statement::Read { var_name } => {
-                    let mut user_input = String::new();
-                    self.input.read_line(&mut user_input)?;
-                    let user_input = user_input.trim();
+#[derive(Debug)]
+enum Statement {
+    Print { value: PrintValue },
+    Read { target: Target },
+    Input { prompt: Option<String>, target: Target },
+    Extension { keyword: String, arguments: String, extension: Extension },
+    Data { values: Vec<Value> },
+    Restore,
+    Let { target: Target, value: Expr },
+    Dim { arrays: Vec<(String, Vec<Expr>)> },
+    Goto { line_number: u16 },
+    Gosub { line_number: u16 },
+    Return,
+    For { var_name: String, start: Expr, end: Expr, step: Option<Expr> },
+    Next { var_name: Option<String> },
+    If { condition: Condition, then: Box<Statement> },
+    /// A comment, kept as written after `REM` or `'`.
+    Rem { text: String, apostrophe: bool },
+    End,
+    Stop,
+}
 
-                    let value = user_input.parse().
-                        map_err(|_| runtime_error!("Not a number: {user_input}"))?;
+/// Something that can be assigned to by LET, READ and INPUT.
+#[derive(Debug)]
+enum Target {
+    Variable(String),
+    Element { name: String, indices: Vec<Expr> },
+}
 
-                    self.variables.insert(var_name.clone(), value);
-                },
-                Statement::Goto { line_number } => {
-                    if self.code_lines.contains_key(line_number) {
-                        current_line_number = *line_number;
-                        continue;
-                    } else {
-                        return Err(runtime_error!("Invalid line number for GOTO: {line_number}"));
-                    }
-                },
-                Statement::If { condition, line_number } => {
-                    let mut parts = condition.split_whitespace();
+#[derive(Debug)]
+enum PrintValue {
+    Expr(Expr),
+    Text(String),
+}
 
-                    let left = self.eval_value(parts.next().unwrap()).
-                        map_err(|e| runtime_error!("{:?}", e))?;
-                    let op = parts.next().unwrap();
-                    let right = self.eval_value(parts.next().unwrap()).
-                        map_err(|e| runtime_error!("{:?}", e))?;
+#[derive(Debug)]
+enum Expr {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Variable(String),
+    Element { name: String, indices: Vec<Expr> },
+    Call { name: String, arguments: Vec<Expr> },
+    Negate(Box<Expr>),
+    Binary { op: BinaryOp, left: Box<Expr>, right: Box<Expr> },
+}
 
-                    let result = match op {
-                        ">" => if left > right { 1 } else { 0 },
-                        "<" => if left < right { 1 } else { 0 },
-                        "=" => if left == right { 1 } else { 0 },
-                        _ => unreachable!(),
-                    };
+#[derive(Debug)]
+enum Condition {
+    Compare { left: Expr, op: CompareOp, right: Expr },
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
 
-                    if result > 0 {
-                        if self.code_lines.contains_key(line_number) {
-                            current_line_number = *line_number;
-                            continue;
-                        } else {
-                            return Err(runtime_error!("Invalid line number for GOTO: {line_number}"));
-                        }
+/// Longer lines are rejected, which also limits how deeply expressions can be nested.
+const MAX_LINE_TOKENS: usize = 256;
+
+/// Words with a meaning of their own in expressions and conditions, so they can't be variables.
+const KEYWORDS: [&str; 7] = ["AND", "OR", "NOT", "THEN", "GOTO", "TO", "STEP"];
+
+/// The keywords of the built-in statements, which extension statements can't take over.
+const STATEMENT_KEYWORDS: [&str; 16] = [
+    "PRINT", "LET", "READ", "INPUT", "DATA", "RESTORE", "DIM", "GOTO", "GOSUB", "RETURN", "FOR", "NEXT",
+    "IF", "REM", "END", "STOP",
+];
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Print { value: PrintValue::Expr(expr) } => write!(f, "PRINT {expr}"),
+            Self::Print { value: PrintValue::Text(text) } => write!(f, "PRINT {text}"),
+            Self::Read { target } => write!(f, "READ {target}"),
+            Self::Input { prompt: Some(prompt), target } => write!(f, "INPUT \"{prompt}\"; {target}"),
+            Self::Input { prompt: None, target } => write!(f, "INPUT {target}"),
+            Self::Data { values } => {
+                write!(f, "DATA ")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
                     }
-                },
-            }
+                    match value {
+                        Value::Str(string) => write!(f, "\"{string}\"")?,
+                        number => write!(f, "{number}")?,
+                    }
+                }
+                Ok(())
+            },
+            Self::Restore => write!(f, "RESTORE"),
+            Self::Extension { keyword, arguments, .. } if arguments.is_empty() => write!(f, "{keyword}"),
+            Self::Extension { keyword, arguments, .. } => write!(f, "{keyword} {arguments}"),
+            Self::Let { target, value } => write!(f, "LET {target} = {value}"),
+            Self::Dim { arrays } => {
+                write!(f, "DIM ")?;
+                for (index, (name, bounds)) in arrays.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_element(f, name, bounds)?;
+                }
+                Ok(())
+            },
+            Self::Goto { line_number } => write!(f, "GOTO {line_number}"),
+            Self::Gosub { line_number } => write!(f, "GOSUB {line_number}"),
+            Self::Return => write!(f, "RETURN"),
+            Self::For { var_name, start, end, step } => {
+                write!(f, "FOR {var_name} = {start} TO {end}")?;
+                if let Some(step) = step {
+                    write!(f, " STEP {step}")?;
+                }
+                Ok(())
+            },
+            Self::Next { var_name: Some(var_name) } => write!(f, "NEXT {var_name}"),
+            Self::Next { var_name: None } => write!(f, "NEXT"),
+            Self::If { condition, then } => {
+                match then.as_ref() {
+                    Self::Goto { line_number } => write!(f, "IF {condition} GOTO {line_number}"),
+                    then => write!(f, "IF {condition} THEN {then}"),
+                }
+            },
+            Self::Rem { text, apostrophe: true } => write!(f, "'{text}"),
+            Self::Rem { text, apostrophe: false } if text.is_empty() => write!(f, "REM"),
+            Self::Rem { text, apostrophe: false } => write!(f, "REM {text}"),
+            Self::End => write!(f, "END"),
+            Self::Stop => write!(f, "STOP"),
+        }
+    }
+}
 
-            let line_number_index = line_numbers.binary_search(&current_line_number).
-                map_err(|_| runtime_error!("Couldn't find line number {current_line_number}"))?;
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Variable(name) => write!(f, "{name}"),
+            Self::Element { name, indices } => fmt_element(f, name, indices),
+        }
+    }
+}
 
-            if let Some(line_number) = line_numbers.get(line_number_index + 1) {
-                current_line_number = *line_number;
-            } else {
-                break;
-            }
+impl Target {
+    fn value_type(&self) -> ValueType {
+        match self {
+            Self::Variable(name) | Self::Element { name, .. } => variable_type(name),
         }
+    }
+}
 
-        Ok(())
+fn fmt_element(f: &mut fmt::Formatter, name: &str, indices: &[Expr]) -> fmt::Result {
+    write!(f, "{name}(")?;
+    for (index, expr) in indices.iter().enumerate() {
+        if index > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{expr}")?;
     }
+    write!(f, ")")
+}
 
-    pub fn eval_value(&self, value: &str) -> Result<u16, InterpreterError> {
-        let first_char = value.chars().next().unwrap();
+impl Statement {
+    /// The line this statement may jump to, looking inside IF ... THEN.
+    fn jump_target(&self) -> Option<u16> {
+        match self {
+            Self::Goto { line_number } | Self::Gosub { line_number } => Some(*line_number),
+            Self::If { then, .. } => then.jump_target(),
+            _ => None,
+        }
+    }
 
-        if first_char.is_uppercase() {
-            self.variables.get(value).
-                ok_or_else(|| InterpreterError::UnknownVariable { name: value.to_string() }).
-                map(|n| *n)
+    fn jump_target_mut(&mut self) -> Option<&mut u16> {
+        match self {
+            Self::Goto { line_number } | Self::Gosub { line_number } => Some(line_number),
+            Self::If { then, .. } => then.jump_target_mut(),
+            _ => None,
+        }
+    }
+}
+
+impl Condition {
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Or(..) => 1,
+            Self::And(..) => 2,
+            Self::Not(_) => 3,
+            Self::Compare { .. } => 4,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({self})")
         } else {
-            value.trim().parse().
-                map_err(|_| InterpreterError::NotANumber { value: value.to_string() })
+            write!(f, "{self}")
         }
     }
 }
 
-fn parse_code_line(input: &str) -> Result<(u16, Statement), InterpreterError> {
-    macro_rules! syntax_error {
-        () => { InterpreterError::SyntaxError { code: input.to_owned() } }
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Compare { left, op, right } => write!(f, "{left} {op} {right}"),
+            Self::Not(inner) => {
+                write!(f, "NOT ")?;
+                inner.fmt_operand(f, 3)
+            },
+            Self::And(left, right) => {
+                left.fmt_operand(f, 2)?;
+                write!(f, " AND ")?;
+                right.fmt_operand(f, 3)
+            },
+            Self::Or(left, right) => {
+                left.fmt_operand(f, 1)?;
+                write!(f, " OR ")?;
+                right.fmt_operand(f, 2)
+            },
+        }
     }
+}
 
-    let parts: Vec<&str> = input.split_whitespace().collect();
-    let line_number = parts.get(0).
-        ok_or_else(|| syntax_error!())?.
-        parse().
-        map_err(|_| syntax_error!())?;
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Add | Self::Sub => 1,
+            Self::Mul | Self::Div => 2,
+        }
+    }
+}
 
-    let statement =
-        match parts.get(1) {
-            Some(&"PRINT") => {
-                let value = parts.get(2).ok_or_else(|| syntax_error!())?.to_string();
-                if parts.len() > 3 {
-                    return Err(syntax_error!());
-                }
-                Statement::Print { value }
+impl Expr {
+    /// Writes the expression, adding parentheses only where `precedence` requires them.
+    fn fmt_with_precedence(&self, f: &mut fmt::Formatter, precedence: u8) -> fmt::Result {
+        match self {
+            Self::Int(number) => write!(f, "{number}"),
+            Self::Float(number) => write!(f, "{number:?}"),
+            Self::Str(string) => write!(f, "\"{string}\""),
+            Self::Variable(name) => write!(f, "{name}"),
+            Self::Element { name, indices } => fmt_element(f, name, indices),
+            Self::Call { name, arguments } => fmt_element(f, name, arguments),
+            Self::Negate(inner) => {
+                write!(f, "-")?;
+                inner.fmt_with_precedence(f, 3)
             },
-            Some(&"READ") => {
-                let var_name = parts.get(2).ok_or_else(|| syntax_error!())?.to_string();
-                if !var_name.chars().next().unwrap().is_uppercase() {
-                    return Err(syntax_error!());
+            Self::Binary { op, left, right } => {
+                let op_precedence = op.precedence();
+                if op_precedence < precedence {
+                    write!(f, "(")?;
                 }
-                if parts.len() > 3 {
-                    return Err(syntax_error!());
+
+                left.fmt_with_precedence(f, op_precedence)?;
+                write!(f, " {op} ")?;
+                right.fmt_with_precedence(f, op_precedence + 1)?;
+
+                if op_precedence < precedence {
+                    write!(f, ")")?;
                 }
-                Statement::Read { var_name }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Expr {
+    /// The type this expression evaluates to, or `None` if it mixes strings and numbers.
+    /// Strings only support `+` for concatenation.
+    fn value_type(&self) -> Option<ValueType> {
+        match self {
+            Self::Int(_) | Self::Float(_) => Some(ValueType::Number),
+            Self::Str(_) => Some(ValueType::Str),
+            Self::Variable(name) => Some(variable_type(name)),
+            Self::Element { name, indices } => {
+                let all_numeric = indices.iter().all(|index| index.value_type() == Some(ValueType::Number));
+                Some(variable_type(name)).filter(|_| all_numeric)
             },
-            Some(&"GOTO") => {
-                let line_number = parts.get(2).ok_or_else(|| syntax_error!())?.to_string().
-                    parse().map_err(|_| syntax_error!())?;
-                if parts.len() > 3 {
-                    return Err(syntax_error!());
+            Self::Call { name, arguments } => {
+                let all_typed = arguments.iter().all(|argument| argument.value_type().is_some());
+                Some(variable_type(name)).filter(|_| all_typed)
+            },
+            Self::Negate(inner) => inner.value_type().filter(|t| *t == ValueType::Number),
+            Self::Binary { op, left, right } => {
+                let value_type = left.value_type()?;
+                if right.value_type()? != value_type {
+                    return None;
+                }
+
+                match (op, value_type) {
+                    (_, ValueType::Number) | (BinaryOp::Add, ValueType::Str) => Some(value_type),
+                    _ => None,
                 }
-                Statement::Goto { line_number }
             },
-            Some(&"IF") => {
-                let condition = parts[2..=4].join(" ");
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with_precedence(f, 0)
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Add => write!(f, "+"),
+            Self::Sub => write!(f, "-"),
+            Self::Mul => write!(f, "*"),
+            Self::Div => write!(f, "/"),
+        }
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Greater        => write!(f, ">"),
+            Self::GreaterOrEqual => write!(f, ">="),
+            Self::Less           => write!(f, "<"),
+            Self::LessOrEqual    => write!(f, "<="),
+            Self::Equal          => write!(f, "="),
+            Self::NotEqual       => write!(f, "<>"),
+        }
+    }
+}
+
+const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+/// The seed `RND` starts from unless the host picks another one with `Interpreter::set_seed`.
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+const BUILTINS: [(&str, Builtin); 6] = [
+    ("ABS", Builtin::Abs),
+    ("MOD", Builtin::Mod),
+    ("MIN", Builtin::Min),
+    ("MAX", Builtin::Max),
+    ("RND", Builtin::Rnd),
+    ("SQR", Builtin::Sqr),
+];
+
+/// A function the host makes callable from BASIC with `Interpreter::register_function`. Errors
+/// are reported as runtime errors of the line calling it.
+pub type NativeFunction = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// A statement keyword the host adds with `Interpreter::register_statement`, e.g. `BEEP`, or
+/// `MOVE` with the argument `NORTH`.
+pub trait StatementExtension {
+    /// Checks the text following the keyword and returns it the way LIST should show it, or
+    /// `None` if it's a syntax error. Anything is accepted by default.
+    fn parse(&self, arguments: &str) -> Option<String> {
+        Some(arguments.to_owned())
+    }
+
+    /// Runs the statement with the arguments returned by `parse`. Changes to `variables` are
+    /// visible to the rest of the program. An error becomes a runtime error of the line.
+    fn execute(
+        &self,
+        arguments: &str,
+        variables: &mut HashMap<String, Value>,
+        output: &mut dyn Write,
+    ) -> Result<(), String>;
+}
+
+#[derive(Clone)]
+struct Extension(Rc<dyn StatementExtension>);
+
+impl fmt::Debug for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Extension")
+    }
+}
+
+/// The functions and extension statements known to the parser, beyond the core statements.
+struct Extensions {
+    functions: HashMap<String, Function>,
+    statements: HashMap<String, Extension>,
+}
+
+#[derive(Clone)]
+enum Function {
+    Builtin(Builtin),
+    Native(Rc<NativeFunction>),
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Builtin(builtin) => write!(f, "Builtin({builtin:?})"),
+            Self::Native(_) => write!(f, "Native"),
+        }
+    }
+}
+
+/// A single bytecode instruction. Every BASIC line compiles to exactly one instruction, so the
+/// instruction index doubles as the position of the line in the sorted program.
+#[derive(Debug)]
+enum Instruction {
+    Print(Vec<Op>),
+    PrintText(String),
+    Read(Place),
+    Input { prompt: Option<String>, place: Place },
+    Extension { extension: Extension, arguments: String },
+    /// DATA lines do nothing when executed; their values are collected into `Program::data`.
+    Data,
+    /// Comments do nothing either.
+    Rem,
+    Restore,
+    Let(Place, Vec<Op>),
+    Dim(Vec<(usize, Vec<Vec<Op>>)>),
+    Jump(usize),
+    Gosub(usize),
+    Return,
+    For {
+        slot: usize,
+        start: Vec<Op>,
+        end: Vec<Op>,
+        step: Option<Vec<Op>>,
+        exit: Option<usize>,
+    },
+    Next(Option<usize>),
+    If { condition: Vec<Op>, then: Box<Instruction> },
+    Stop,
+    Fail(String),
+    Halt,
+}
+
+/// A compiled `Target`: either a variable slot or an element of an array slot.
+#[derive(Debug)]
+enum Place {
+    Slot(usize),
+    Element { array: usize, indices: Vec<Vec<Op>> },
+}
+
+/// The name of the variable or array a place belongs to.
+fn place_name<'a>(program: &'a Program, place: &Place) -> &'a str {
+    match place {
+        Place::Slot(slot) => &program.slot_names[*slot],
+        Place::Element { array, .. } => &program.array_names[*array],
+    }
+}
+
+/// Expression bytecode for a small stack machine, in postfix order. Conditions evaluate to 1 for
+/// true and 0 for false.
+#[derive(Debug, Clone)]
+enum Op {
+    Push(Value),
+    Load(usize),
+    LoadElement { array: usize, dimensions: usize },
+    Call { function: usize, arguments: usize },
+    Negate,
+    Binary(BinaryOp),
+    Compare(CompareOp),
+    Not,
+    And,
+    Or,
+}
+
+/// The result of compiling `code_lines`: jump targets are instruction indices and variables are
+/// interned into numbered slots.
+#[derive(Debug)]
+struct Program {
+    instructions: Vec<Instruction>,
+    line_numbers: Vec<u16>,
+    slot_names: Vec<String>,
+    array_names: Vec<String>,
+    functions: Vec<(String, Function)>,
+    data: Vec<Value>,
+}
+
+#[derive(Debug)]
+struct ForLoop {
+    slot: usize,
+    end: Value,
+    step: Value,
+    body: usize,
+}
+
+/// Execution state of a compiled program.
+#[derive(Debug)]
+struct Machine {
+    program: Rc<Program>,
+    slots: Vec<Option<Value>>,
+    arrays: Vec<Option<Array>>,
+    pc: usize,
+    call_stack: Vec<usize>,
+    loop_stack: Vec<ForLoop>,
+    data_position: usize,
+    random_state: Cell<u64>,
+    steps: u64,
+    inputs: u64,
+    /// Set when INPUT suspended the program to wait for input, so the prompt isn't repeated.
+    awaiting_input: bool,
+    /// Set by STOP until the next instruction is executed.
+    stopped_at: Option<u16>,
+    line_counts: Vec<u64>,
+    back_jumps: HashMap<(usize, usize), u64>,
+}
+
+impl Machine {
+    fn new(program: Program, variables: &HashMap<String, Value>, seed: u64) -> Self {
+        let slots = program.slot_names.iter().map(|name| variables.get(name).cloned()).collect();
+        let arrays = program.array_names.iter().map(|_| None).collect();
+        let line_counts = vec![0; program.instructions.len()];
+        Self {
+            program: Rc::new(program),
+            slots,
+            arrays,
+            pc: 0,
+            call_stack: Vec::new(),
+            loop_stack: Vec::new(),
+            data_position: 0,
+            random_state: Cell::new(seed),
+            steps: 0,
+            inputs: 0,
+            awaiting_input: false,
+            stopped_at: None,
+            line_counts,
+            back_jumps: HashMap::new(),
+        }
+    }
+
+    fn current_line(&self) -> u16 {
+        self.program.line_numbers[self.pc]
+    }
+
+    fn is_halted(&self) -> bool {
+        matches!(self.program.instructions[self.pc], Instruction::Halt)
+    }
+
+    fn set_variable(&mut self, name: &str, value: Value) {
+        if let Some(slot) = self.program.slot_names.iter().position(|n| n == name) {
+            self.slots[slot] = Some(value);
+        }
+    }
+
+    fn store_variables(&self, variables: &mut HashMap<String, Value>) {
+        for (name, value) in self.program.slot_names.iter().zip(&self.slots) {
+            if let Some(value) = value {
+                variables.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    fn profile(&self) -> Profile {
+        // Only the instructions before `Halt` are lines; the ones after it are `Fail` stubs.
+        let line_count = self.program.instructions.iter().
+            position(|instruction| matches!(instruction, Instruction::Halt)).
+            expect("compiled programs end with Halt");
+        let line_counts = self.program.line_numbers[..line_count].iter().
+            copied().
+            zip(self.line_counts.iter().copied()).
+            collect::<Vec<_>>();
+
+        let mut hottest_loops = self.back_jumps.iter().
+            map(|((from, to), iterations)| LoopProfile {
+                first_line: self.program.line_numbers[*to],
+                last_line: self.program.line_numbers[*from],
+                iterations: *iterations,
+                steps_in_range: self.line_counts[*to..=*from].iter().sum(),
+            }).
+            collect::<Vec<_>>();
+        hottest_loops.sort_by_key(|l| (std::cmp::Reverse(l.steps_in_range), l.first_line, l.last_line));
+
+        Profile { line_counts, hottest_loops }
+    }
 
-                if !matches!(parts.get(5), Some(&"GOTO")) {
-                    return Err(syntax_error!());
+    /// Takes over variables changed outside the program, checking they still match their names.
+    fn load_variables(&mut self, variables: &HashMap<String, Value>) -> Result<(), EvalError> {
+        for (name, slot) in self.program.slot_names.iter().zip(&mut self.slots) {
+            if let Some(value) = variables.get(name) {
+                if value.value_type() != variable_type(name) {
+                    return Err(EvalError::TypeMismatch);
                 }
+                *slot = Some(value.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dim(&mut self, array: usize, bounds: Vec<i64>) -> Result<(), EvalError> {
+        self.arrays[array] = Some(Array::new(&self.program.array_names[array], bounds)?);
+        Ok(())
+    }
+
+    /// The position of an element in its array's `values`.
+    fn element_index(&self, array: usize, indices: &[i64]) -> Result<usize, EvalError> {
+        let name = &self.program.array_names[array];
+        self.arrays[array].as_ref().
+            ok_or_else(|| EvalError::UnknownArray(name.clone()))?.
+            position(name, indices)
+    }
 
-                let line_number = parts.get(6).ok_or_else(|| syntax_error!())?.to_string().
-                    parse().map_err(|_| syntax_error!())?;
+    fn eval_indices(&self, indices: &[Vec<Op>]) -> Result<Vec<i64>, EvalError> {
+        indices.iter().map(|code| self.eval(code)?.as_index()).collect()
+    }
 
-                if parts.len() > 7 {
-                    return Err(syntax_error!());
+    fn store(&mut self, place: &Place, value: Value) -> Result<(), EvalError> {
+        match place {
+            Place::Slot(slot) => self.slots[*slot] = Some(value),
+            Place::Element { array, indices } => {
+                let indices = self.eval_indices(indices)?;
+                let position = self.element_index(*array, &indices)?;
+                if let Some(array) = &mut self.arrays[*array] {
+                    array.values[position] = value;
                 }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn eval_number(&self, code: &[Op]) -> Result<Value, EvalError> {
+        match self.eval(code)? {
+            Value::Str(_) => Err(EvalError::TypeMismatch),
+            number => Ok(number),
+        }
+    }
 
-                Statement::If { condition, line_number }
+    fn call(&self, function: usize, arguments: &[Value]) -> Result<Value, EvalError> {
+        let (name, function) = &self.program.functions[function];
+
+        let result = match function {
+            Function::Builtin(builtin) => call_builtin(name, *builtin, arguments, &self.random_state)?,
+            Function::Native(function) => {
+                let result = function(arguments).map_err(|message| EvalError::Native { name: name.clone(), message })?;
+                if !result.is_finite() {
+                    let message = format!("returned {result}, which isn't a finite number");
+                    return Err(EvalError::Native { name: name.clone(), message });
+                }
+                result
             },
-            _ => { return Err(syntax_error!()) }
         };
 
-    Ok((line_number, statement))
+        if result.value_type() != variable_type(name) {
+            return Err(EvalError::TypeMismatch);
+        }
+        Ok(result)
+    }
+
+    fn eval(&self, code: &[Op]) -> Result<Value, EvalError> {
+        let mut stack = Vec::with_capacity(code.len());
+
+        for op in code {
+            let value = match op {
+                Op::Push(value) => value.clone(),
+                Op::Load(slot) => {
+                    self.slots[*slot].clone().
+                        ok_or_else(|| EvalError::UnknownVariable(self.program.slot_names[*slot].clone()))?
+                },
+                Op::LoadElement { array, dimensions } => {
+                    let indices = stack.split_off(stack.len() - dimensions).
+                        iter().
+                        map(Value::as_index).
+                        collect::<Result<Vec<_>, _>>()?;
+
+                    let position = self.element_index(*array, &indices)?;
+                    self.arrays[*array].as_ref().map(|a| a.values[position].clone()).
+                        ok_or_else(|| EvalError::UnknownArray(self.program.array_names[*array].clone()))?
+                },
+                Op::Call { function, arguments } => {
+                    let arguments = stack.split_off(stack.len() - arguments);
+                    self.call(*function, &arguments)?
+                },
+                Op::Negate => stack.pop().expect("expression code is balanced").negate()?,
+                Op::Binary(op) => {
+                    let right = stack.pop().expect("expression code is balanced");
+                    let left = stack.pop().expect("expression code is balanced");
+
+                    left.arithmetic(*op, right)?
+                },
+                Op::Compare(op) => {
+                    let right = stack.pop().expect("expression code is balanced");
+                    let left = stack.pop().expect("expression code is balanced");
+
+                    Value::Int(op.holds(left.compare(&right)?) as i64)
+                },
+                Op::Not => {
+                    let value = stack.pop().expect("expression code is balanced");
+                    Value::Int(!value.is_true() as i64)
+                },
+                Op::And | Op::Or => {
+                    let right = stack.pop().expect("expression code is balanced").is_true();
+                    let left = stack.pop().expect("expression code is balanced").is_true();
+
+                    let result = if matches!(op, Op::And) { left && right } else { left || right };
+                    Value::Int(result as i64)
+                },
+            };
+            stack.push(value);
+        }
+
+        Ok(stack.pop().expect("expression code is balanced"))
+    }
+}
+
+/// The transcript of a run being recorded: `IN` for every line read by INPUT, `OUT` for every
+/// line printed, and `PROMPT` for output still waiting for its newline when input is read or the
+/// program ends. A failed run ends with `ERROR` and the error message, and a run ended by STOP
+/// with `STOP` and the line number.
+struct Recording {
+    transcript: Vec<String>,
+    /// Output since the last newline.
+    partial_line: Vec<u8>,
+    /// While replaying, the only lines INPUT gets to read.
+    replay_input: Option<VecDeque<String>>,
+}
+
+impl Recording {
+    fn new(replay_input: Option<VecDeque<String>>) -> Self {
+        Self { transcript: Vec::new(), partial_line: Vec::new(), replay_input }
+    }
+
+    fn output(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if *byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.partial_line).into_owned();
+                self.transcript.push(format!("OUT {line}"));
+                self.partial_line.clear();
+            } else {
+                self.partial_line.push(*byte);
+            }
+        }
+    }
+
+    fn flush_prompt(&mut self) {
+        if !self.partial_line.is_empty() {
+            let prompt = String::from_utf8_lossy(&self.partial_line).into_owned();
+            self.transcript.push(format!("PROMPT {prompt}"));
+            self.partial_line.clear();
+        }
+    }
+
+    fn input(&mut self, line: &str) {
+        self.flush_prompt();
+        self.transcript.push(format!("IN {}", line.trim_end_matches(['\r', '\n'])));
+    }
+
+    fn finish(mut self, result: &Result<RunOutcome, InterpreterError>) -> Vec<String> {
+        self.flush_prompt();
+        match result {
+            Ok(RunOutcome::Finished) => {},
+            Ok(RunOutcome::Stopped { line_number }) => self.transcript.push(format!("STOP {line_number}")),
+            Err(e) => self.transcript.push(format!("ERROR {e}")),
+        }
+        self.transcript
+    }
+}
+
+/// The program's output, which also goes into the transcript while recording.
+struct ProgramOutput<'b, W: Write> {
+    output: &'b mut W,
+    recording: Option<&'b mut Recording>,
+}
+
+impl<W: Write> Write for ProgramOutput<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.output.write(buf)?;
+        if let Some(recording) = &mut self.recording {
+            recording.output(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+pub struct Interpreter<'a, R: Read, W: Write> {
+    code_lines: HashMap<u16, Statement>,
+    variables: HashMap<String, Value>,
+    extensions: Extensions,
+    seed: u64,
+    max_call_depth: usize,
+    limits: Limits,
+    breakpoints: HashSet<u16>,
+    session: Option<Machine>,
+    trace: Option<Trace<'a>>,
+    profile: Option<Profile>,
+    fed_input: VecDeque<String>,
+    suspend_on_input: bool,
+    recording: Option<Recording>,
+    input: BufReader<R>,
+    output: &'a mut W,
+}
+
+impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
+    pub fn new(input: R, output: &'a mut W) -> Self {
+        Self {
+            code_lines: HashMap::new(),
+            variables: HashMap::new(),
+            extensions: Extensions {
+                functions: BUILTINS.iter().
+                    map(|(name, builtin)| (name.to_string(), Function::Builtin(*builtin))).
+                    collect(),
+                statements: HashMap::new(),
+            },
+            seed: DEFAULT_SEED,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            limits: Limits::default(),
+            breakpoints: HashSet::new(),
+            session: None,
+            trace: None,
+            profile: None,
+            fed_input: VecDeque::new(),
+            suspend_on_input: false,
+            recording: None,
+            input: BufReader::new(input),
+            output,
+        }
+    }
+
+    pub fn add(&mut self, code: &str) -> Result<u16, InterpreterError> {
+        let (line_number, statement) = parse_code_line(code, &self.extensions).
+            map_err(|e| e.into_interpreter_error(code))?;
+
+        self.code_lines.insert(line_number, statement);
+        self.session = None;
+
+        Ok(line_number)
+    }
+
+    /// Parses a whole program text, one BASIC line per line of text, and replaces the current
+    /// program with it. Blank lines are skipped. If any line is invalid, every error is returned
+    /// and the program stays as it was.
+    pub fn load(&mut self, text: &str) -> Result<(), Vec<ParseError>> {
+        let mut code_lines = HashMap::new();
+        let mut errors = Vec::new();
+
+        for (index, code) in text.lines().enumerate().filter(|(_, code)| !code.trim().is_empty()) {
+            match parse_code_line(code, &self.extensions) {
+                Ok((line_number, statement)) => {
+                    code_lines.insert(line_number, statement);
+                },
+                Err(e) => errors.push(e.into_parse_error(index + 1, code)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        self.code_lines = code_lines;
+        self.session = None;
+        Ok(())
+    }
+
+    /// Removes a line from the program. Returns whether the line existed.
+    pub fn remove(&mut self, line_number: u16) -> bool {
+        self.session = None;
+        self.code_lines.remove(&line_number).is_some()
+    }
+
+    /// Removes every line in the range. Returns how many lines were removed.
+    pub fn remove_range(&mut self, range: impl RangeBounds<u16>) -> usize {
+        self.session = None;
+
+        let line_count = self.code_lines.len();
+        self.code_lines.retain(|line_number, _| !range.contains(line_number));
+        line_count - self.code_lines.len()
+    }
+
+    /// Renumbers the program from `start` in steps of `step`, rewriting GOTO, GOSUB and IF
+    /// targets to match. Breakpoints move with their lines. Jumps to missing lines keep their
+    /// target, so this fails if such a target would become one of the new line numbers.
+    pub fn renumber(&mut self, start: u16, step: u16) -> Result<(), InterpreterError> {
+        let fail = |message: String| InterpreterError::RenumberFailed { message };
+
+        if step == 0 {
+            return Err(fail("step must not be zero".to_owned()));
+        }
+
+        let mut line_numbers = self.code_lines.keys().copied().collect::<Vec<_>>();
+        line_numbers.sort();
+
+        let new_line_numbers = line_numbers.iter().
+            enumerate().
+            map(|(index, line_number)| {
+                let new_line_number = u16::try_from(start as usize + index * step as usize).ok()?;
+                Some((*line_number, new_line_number))
+            }).
+            collect::<Option<HashMap<_, _>>>().
+            ok_or_else(|| fail(format!("line numbers would exceed {}", u16::MAX)))?;
+        let taken = new_line_numbers.values().copied().collect::<HashSet<_>>();
+
+        for line_number in &line_numbers {
+            let target = self.code_lines[line_number].jump_target();
+            if let Some(target) = target.filter(|t| !new_line_numbers.contains_key(t) && taken.contains(t)) {
+                return Err(fail(format!(
+                    "line {line_number} jumps to missing line {target}, which would become a renumbered line"
+                )));
+            }
+        }
+
+        self.code_lines = self.code_lines.drain().
+            map(|(line_number, mut statement)| {
+                if let Some(target) = statement.jump_target_mut() {
+                    *target = new_line_numbers.get(target).copied().unwrap_or(*target);
+                }
+                (new_line_numbers[&line_number], statement)
+            }).
+            collect();
+        self.breakpoints = self.breakpoints.iter().
+            filter_map(|line_number| new_line_numbers.get(line_number).copied()).
+            collect();
+        self.session = None;
+
+        Ok(())
+    }
+
+    /// Forgets the whole program along with all variables.
+    pub fn clear(&mut self) {
+        self.session = None;
+        self.code_lines.clear();
+        self.variables.clear();
+    }
+
+    /// The program in line number order, formatted the way it would be typed in.
+    pub fn list(&self) -> Vec<String> {
+        let mut line_numbers = self.code_lines.keys().collect::<Vec<_>>();
+        line_numbers.sort();
+
+        line_numbers.into_iter().
+            map(|n| format!("{n} {}", self.code_lines[n])).
+            collect()
+    }
+
+    /// Statically checks the whole program and reports every problem found, in line order.
+    /// Variables that already have a value, e.g. from a previous run, count as assigned.
+    pub fn check(&self) -> Vec<Diagnostic> {
+        check_program(&self.code_lines, &self.extensions.functions, &self.variables)
+    }
+
+    /// Translates the program into the source of a standalone Rust program that behaves like
+    /// `run`, reading INPUT from stdin and printing to stdout. Runtime errors are printed to stderr
+    /// the way `run` reports them and exit with status 1, and so does STOP, as `Stopped in line N`,
+    /// since there's no way to continue it. The current variables and seed
+    /// become the initial state. Limits, breakpoints and tracing don't carry over, and programs
+    /// using native functions or extension statements can't be translated.
+    pub fn to_rust(&self) -> Result<String, InterpreterError> {
+        let program = compile(&self.code_lines, &self.extensions.functions);
+        transpile(&program, &self.code_lines, &self.variables, self.seed, self.max_call_depth)
+    }
+
+    /// Makes a native function callable from BASIC expressions, replacing any function of the
+    /// same name. Names are spelled like variables, and names ending in `$` must return strings.
+    /// Lines are parsed against the functions known when they're added, so register functions
+    /// before adding lines that call them.
+    pub fn register_function<F>(&mut self, name: &str, function: F) -> Result<(), InterpreterError>
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let invalid = |reason: &str| InterpreterError::InvalidName { name: name.to_owned(), reason: reason.to_owned() };
+
+        if !matches!(tokenize(name).as_slice(), [(_, Token::Name(token))] if token == name) {
+            return Err(invalid("function names start with an uppercase letter followed by letters and digits"));
+        }
+        if KEYWORDS.contains(&name) {
+            return Err(invalid("reserved keyword"));
+        }
+
+        self.session = None;
+        self.extensions.functions.insert(name.to_owned(), Function::Native(Rc::new(function)));
+        Ok(())
+    }
+
+    /// Adds a statement keyword handled by the host. Keywords are single words of uppercase
+    /// letters and digits, and can't be the keyword of a built-in statement. Like functions,
+    /// extensions have to be registered before adding lines that use them.
+    pub fn register_statement(
+        &mut self,
+        keyword: &str,
+        extension: impl StatementExtension + 'static,
+    ) -> Result<(), InterpreterError> {
+        let invalid = |reason: &str| InterpreterError::InvalidName { name: keyword.to_owned(), reason: reason.to_owned() };
+
+        if !keyword.starts_with(char::is_uppercase) || !keyword.chars().all(|c| c.is_uppercase() || c.is_ascii_digit()) {
+            return Err(invalid("statement keywords are single words of uppercase letters and digits"));
+        }
+        if STATEMENT_KEYWORDS.contains(&keyword) || KEYWORDS.contains(&keyword) {
+            return Err(invalid("reserved keyword"));
+        }
+
+        self.session = None;
+        self.extensions.statements.insert(keyword.to_owned(), Extension(Rc::new(extension)));
+        Ok(())
+    }
+
+    /// Seeds `RND`. Every run starts from the seed again, so the same program with the same
+    /// input always produces the same output.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Limits how many GOSUB calls can be nested before `run` fails with a stack overflow.
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Runs the program from the start. If it executes STOP, this returns
+    /// `RunOutcome::Stopped` and `cont` continues after the STOP.
+    pub fn run(&mut self) -> Result<RunOutcome, InterpreterError> {
+        self.session = None;
+        let program = compile(&self.code_lines, &self.extensions.functions);
+        let machine = Machine::new(program, &self.variables, self.seed);
+
+        self.run_machine(machine)
+    }
+
+    /// Continues the current session until the program finishes or stops again, like `run`.
+    /// Sessions are left by STOP, debugging and `run_for`, and end whenever a line changes.
+    pub fn cont(&mut self) -> Result<RunOutcome, InterpreterError> {
+        let machine = self.session.take().ok_or(InterpreterError::CannotContinue)?;
+        self.run_machine(machine)
+    }
+
+    fn run_machine(&mut self, mut machine: Machine) -> Result<RunOutcome, InterpreterError> {
+        let started = Instant::now();
+
+        let result = loop {
+            if self.limits.max_duration.is_some_and(|max| started.elapsed() > max) {
+                break Err(InterpreterError::BudgetExceeded {
+                    budget: Budget::Time,
+                    line_number: machine.current_line(),
+                    steps: machine.steps,
+                });
+            }
+
+            match self.execute(&mut machine) {
+                Ok(true) => {
+                    if let Some(line_number) = machine.stopped_at {
+                        break Ok(RunOutcome::Stopped { line_number });
+                    }
+                },
+                Ok(false) => break Ok(RunOutcome::Finished),
+                Err(e) => break Err(e),
+            }
+        };
+
+        machine.store_variables(&mut self.variables);
+        self.profile = Some(machine.profile());
+        if matches!(result, Ok(RunOutcome::Stopped { .. })) {
+            self.session = Some(machine);
+        }
+        result
+    }
+
+    /// Executes up to `steps` lines of the current session, starting a new session if there is
+    /// none. Debugging sessions can be continued this way too, but breakpoints are ignored.
+    pub fn run_for(&mut self, steps: u64) -> RunStatus {
+        let mut machine = self.take_session();
+        let mut status = RunStatus::BudgetExhausted;
+
+        self.suspend_on_input = true;
+        for _ in 0..steps {
+            match self.execute(&mut machine) {
+                Ok(true) if machine.awaiting_input => {
+                    status = RunStatus::NeedsInput;
+                    break;
+                },
+                Ok(true) => {
+                    if let Some(line_number) = machine.stopped_at {
+                        status = RunStatus::Stopped { line_number };
+                        break;
+                    }
+                },
+                Ok(false) => {
+                    status = RunStatus::Finished;
+                    break;
+                },
+                Err(e) => {
+                    status = RunStatus::Error(e);
+                    break;
+                },
+            }
+        }
+        self.suspend_on_input = false;
+
+        if matches!(status, RunStatus::BudgetExhausted) && machine.is_halted() {
+            status = RunStatus::Finished;
+        }
+
+        machine.store_variables(&mut self.variables);
+        match status {
+            RunStatus::NeedsInput | RunStatus::BudgetExhausted | RunStatus::Stopped { .. } => {
+                self.session = Some(machine)
+            },
+            RunStatus::Finished | RunStatus::Error(_) => self.profile = Some(machine.profile()),
+        }
+        status
+    }
+
+    /// Saves the program, variables, queued input, breakpoints and the current session, if any,
+    /// as text that `restore` turns back into the same state. Profiles aren't saved.
+    pub fn snapshot(&self) -> String {
+        let mut lines = vec![SNAPSHOT_HEADER.to_owned()];
+
+        lines.extend(self.list().into_iter().map(|code| format!("LINE {code}")));
+
+        let mut names = self.variables.keys().collect::<Vec<_>>();
+        names.sort();
+        lines.extend(names.into_iter().map(|name| format!("VAR {name} = {}", fmt_snapshot_value(&self.variables[name]))));
+
+        let mut breakpoints = self.breakpoints.iter().collect::<Vec<_>>();
+        breakpoints.sort();
+        lines.extend(breakpoints.into_iter().map(|line_number| format!("BREAK {line_number}")));
+
+        lines.extend(self.fed_input.iter().map(|line| format!("INPUT {line}")));
+        lines.push(format!("SEED {}", self.seed));
+
+        if let Some(machine) = &self.session {
+            lines.push(format!("PC {}", machine.pc));
+            lines.extend(machine.call_stack.iter().map(|pc| format!("CALL {pc}")));
+
+            for for_loop in &machine.loop_stack {
+                lines.push(format!(
+                    "LOOP {} {} {}, {}",
+                    machine.program.slot_names[for_loop.slot],
+                    for_loop.body,
+                    fmt_snapshot_value(&for_loop.end),
+                    fmt_snapshot_value(&for_loop.step),
+                ));
+            }
+
+            for (name, array) in machine.program.array_names.iter().zip(&machine.arrays) {
+                if let Some(array) = array {
+                    let bounds = array.bounds.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",");
+                    let values = array.values.iter().map(fmt_snapshot_value).collect::<Vec<_>>().join(", ");
+                    lines.push(format!("ARRAY {name} {bounds} = {values}"));
+                }
+            }
+
+            lines.push(format!("DATA {}", machine.data_position));
+            lines.push(format!("RANDOM {}", machine.random_state.get()));
+            lines.push(format!("STEPS {}", machine.steps));
+            lines.push(format!("INPUTS {}", machine.inputs));
+            if machine.awaiting_input {
+                lines.push("AWAITING".to_owned());
+            }
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Replaces the whole state with a `snapshot`. Lines are parsed again, so functions and
+    /// statements used by the program have to be registered first. Nothing changes on error.
+    pub fn restore(&mut self, snapshot: &str) -> Result<(), InterpreterError> {
+        let invalid = |line: &str| InterpreterError::InvalidSnapshot { line: line.to_owned() };
+
+        let mut lines = snapshot.lines();
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return Err(invalid(snapshot.lines().next().unwrap_or_default()));
+        }
+
+        let mut code_lines = HashMap::new();
+        let mut variables = HashMap::new();
+        let mut breakpoints = HashSet::new();
+        let mut fed_input = VecDeque::new();
+        let mut seed = DEFAULT_SEED;
+        let mut session_lines = Vec::new();
+
+        for line in lines {
+            let (tag, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+            match tag {
+                "LINE" => {
+                    let (line_number, statement) = parse_code_line(rest, &self.extensions).
+                        map_err(|e| e.into_interpreter_error(rest))?;
+                    code_lines.insert(line_number, statement);
+                },
+                "VAR" => {
+                    let (name, value) = rest.split_once(" = ").ok_or_else(|| invalid(line))?;
+                    let values = parse_snapshot_values(value).ok_or_else(|| invalid(line))?;
+                    let [value] = <[Value; 1]>::try_from(values).map_err(|_| invalid(line))?;
+
+                    if value.value_type() != variable_type(name) {
+                        return Err(invalid(line));
+                    }
+                    variables.insert(name.to_owned(), value);
+                },
+                "BREAK" => {
+                    breakpoints.insert(rest.parse().map_err(|_| invalid(line))?);
+                },
+                "INPUT" => fed_input.push_back(rest.to_owned()),
+                "SEED" => seed = rest.parse().map_err(|_| invalid(line))?,
+                _ => session_lines.push(line),
+            }
+        }
+
+        let session = if session_lines.is_empty() {
+            None
+        } else {
+            let program = compile(&code_lines, &self.extensions.functions);
+            let mut machine = Machine::new(program, &variables, seed);
+            for line in session_lines {
+                restore_machine_line(&mut machine, line).ok_or_else(|| invalid(line))?;
+            }
+            if machine.awaiting_input && !reads_input(&machine.program.instructions[machine.pc]) {
+                return Err(invalid("AWAITING"));
+            }
+            Some(machine)
+        };
+
+        self.code_lines = code_lines;
+        self.variables = variables;
+        self.breakpoints = breakpoints;
+        self.fed_input = fed_input;
+        self.seed = seed;
+        self.session = session;
+        self.profile = None;
+        Ok(())
+    }
+
+    /// Queues lines of input for INPUT, which uses them before reading from the input reader.
+    /// These are the only lines INPUT gets in `run_for`.
+    pub fn feed_input(&mut self, text: &str) {
+        self.fed_input.extend(text.lines().map(str::to_owned));
+    }
+
+    /// Runs the program like `run`, and writes a transcript of every line INPUT read and every
+    /// line printed to `transcript`, for `replay` to check later runs against.
+    pub fn run_recorded(&mut self, transcript: &mut impl Write) -> Result<RunOutcome, InterpreterError> {
+        self.recording = Some(Recording::new(None));
+        let result = self.run();
+        let recording = self.recording.take().expect("recording was started");
+
+        writeln!(transcript, "{TRANSCRIPT_HEADER}")?;
+        for line in recording.finish(&result) {
+            writeln!(transcript, "{line}")?;
+        }
+        result
+    }
+
+    /// Runs the program with the input of a transcript from `run_recorded` instead of the input
+    /// reader, and fails with `InterpreterError::TranscriptMismatch` at the first transcript line
+    /// the run doesn't reproduce. A run failing with the same error as recorded counts as a match.
+    pub fn replay(&mut self, transcript: &str) -> Result<(), InterpreterError> {
+        let invalid = |line: &str| InterpreterError::InvalidTranscript { line: line.to_owned() };
+
+        let mut lines = transcript.lines();
+        if lines.next() != Some(TRANSCRIPT_HEADER) {
+            return Err(invalid(transcript.lines().next().unwrap_or_default()));
+        }
+
+        let mut expected = Vec::new();
+        let mut input = VecDeque::new();
+        for line in lines {
+            let (tag, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match tag {
+                "IN" => input.push_back(rest.to_owned()),
+                "OUT" | "PROMPT" | "STOP" | "ERROR" => {},
+                _ => return Err(invalid(line)),
+            }
+            expected.push(format!("{tag} {rest}"));
+        }
+
+        self.recording = Some(Recording::new(Some(input)));
+        let result = self.run();
+        let actual = self.recording.take().expect("recording was started").finish(&result);
+
+        let mismatch = (0..expected.len().max(actual.len())).
+            find(|index| expected.get(*index) != actual.get(*index));
+        match mismatch {
+            // Transcript lines count from 1, after the header.
+            Some(index) => Err(InterpreterError::TranscriptMismatch {
+                line: index + 2,
+                expected: expected.get(index).cloned(),
+                actual: actual.get(index).cloned(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Traces every line executed by `run` and debugging sessions, or stops tracing with `None`.
+    pub fn set_trace(&mut self, trace: Option<Trace<'a>>) {
+        self.trace = trace;
+    }
+
+    /// The profile of the last `run`, whether it finished or failed.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn set_breakpoint(&mut self, line_number: u16) {
+        self.breakpoints.insert(line_number);
+    }
+
+    pub fn clear_breakpoint(&mut self, line_number: u16) {
+        self.breakpoints.remove(&line_number);
+    }
+
+    /// Executes a single statement of the current debugging session, starting a new session if
+    /// there is none. Sessions end when the program finishes, fails, or a line is added.
+    pub fn step(&mut self) -> Result<DebugStatus, InterpreterError> {
+        let mut machine = self.take_session();
+        let result = self.execute(&mut machine);
+
+        self.pause(machine, result)
+    }
+
+    /// Runs the current debugging session until it reaches a line with a breakpoint, executes
+    /// STOP or finishes.
+    /// A fresh session stops before its first line if that line has a breakpoint.
+    pub fn resume(&mut self) -> Result<DebugStatus, InterpreterError> {
+        let is_fresh = self.session.is_none();
+        let mut machine = self.take_session();
+
+        if is_fresh && !machine.is_halted() && self.breakpoints.contains(&machine.current_line()) {
+            return self.pause(machine, Ok(true));
+        }
+
+        let result = loop {
+            match self.execute(&mut machine) {
+                Ok(true) if machine.is_halted() || machine.stopped_at.is_some() => break Ok(true),
+                Ok(true) if self.breakpoints.contains(&machine.current_line()) => break Ok(true),
+                Ok(true) => continue,
+                result => break result,
+            }
+        };
+
+        self.pause(machine, result)
+    }
+
+    /// The line the current debugging session will execute next.
+    pub fn current_line(&self) -> Option<u16> {
+        self.session.as_ref().map(Machine::current_line)
+    }
+
+    pub fn variables(&self) -> &HashMap<String, Value> {
+        &self.variables
+    }
+
+    /// Sets a variable, also in the current session. Names ending in `$` only take strings, all
+    /// other names only take finite numbers.
+    pub fn set_variable(&mut self, name: &str, value: Value) -> Result<(), InterpreterError> {
+        if value.value_type() != variable_type(name) {
+            return Err(InterpreterError::TypeMismatch { name: name.to_owned() });
+        }
+        if !value.is_finite() {
+            return Err(InterpreterError::NotANumber { value: value.to_string() });
+        }
+
+        self.variables.insert(name.to_owned(), value.clone());
+
+        if let Some(machine) = &mut self.session {
+            machine.set_variable(name, value);
+        }
+        Ok(())
+    }
+
+    fn take_session(&mut self) -> Machine {
+        self.session.take().
+            unwrap_or_else(|| {
+                Machine::new(compile(&self.code_lines, &self.extensions.functions), &self.variables, self.seed)
+            })
+    }
+
+    fn pause(
+        &mut self,
+        machine: Machine,
+        result: Result<bool, InterpreterError>,
+    ) -> Result<DebugStatus, InterpreterError> {
+        machine.store_variables(&mut self.variables);
+
+        if !result? || machine.is_halted() {
+            return Ok(DebugStatus::Finished);
+        }
+
+        let line_number = machine.current_line();
+        self.session = Some(machine);
+        Ok(DebugStatus::Paused { line_number })
+    }
+
+    /// Executes the instruction at `machine.pc`. Returns `false` once the program has halted.
+    fn execute(&mut self, machine: &mut Machine) -> Result<bool, InterpreterError> {
+        let pc = machine.pc;
+        let line_number = machine.program.line_numbers[pc];
+
+        if !machine.is_halted() && self.limits.max_steps.is_some_and(|max| machine.steps >= max) {
+            return Err(InterpreterError::BudgetExceeded { budget: Budget::Steps, line_number, steps: machine.steps });
+        }
+
+        let program = Rc::clone(&machine.program);
+        let instruction = &program.instructions[pc];
+        machine.stopped_at = None;
+        let slots_before = self.trace.as_ref().filter(|trace| trace.variables).map(|_| machine.slots.clone());
+        machine.pc += 1;
+
+        let result = self.execute_instruction(machine, instruction, line_number);
+        if machine.awaiting_input {
+            return result;
+        }
+
+        if !matches!(result, Ok(false)) {
+            self.trace_line(machine, line_number, slots_before)?;
+        }
+
+        let is_running = result?;
+        if is_running {
+            machine.steps += 1;
+            machine.line_counts[pc] += 1;
+
+            if machine.pc <= pc && is_loop_jump(instruction) {
+                *machine.back_jumps.entry((pc, machine.pc)).or_default() += 1;
+            }
+        }
+        Ok(is_running)
+    }
+
+    fn trace_line(
+        &mut self,
+        machine: &Machine,
+        line_number: u16,
+        slots_before: Option<Vec<Option<Value>>>,
+    ) -> Result<(), InterpreterError> {
+        let Some(trace) = &mut self.trace else {
+            return Ok(());
+        };
+
+        write!(trace.writer, "[{line_number}]")?;
+
+        if let Some(slots_before) = slots_before {
+            let changes = machine.program.slot_names.iter().
+                zip(slots_before.iter().zip(&machine.slots)).
+                filter(|(_, (before, after))| before != after);
+
+            for (index, (name, (_, value))) in changes.enumerate() {
+                let separator = if index == 0 { " " } else { ", " };
+                match value {
+                    Some(Value::Str(string)) => write!(trace.writer, "{separator}{name} = \"{string}\"")?,
+                    Some(value) => write!(trace.writer, "{separator}{name} = {value}")?,
+                    None => {},
+                }
+            }
+        }
+
+        writeln!(trace.writer)?;
+        Ok(())
+    }
+
+    /// Executes a single instruction, with `machine.pc` already pointing past it.
+    fn execute_instruction(
+        &mut self,
+        machine: &mut Machine,
+        instruction: &Instruction,
+        line_number: u16,
+    ) -> Result<bool, InterpreterError> {
+        macro_rules! runtime_error {
+            ($($arg:tt)*) => {
+                InterpreterError::RuntimeError {
+                    line_number,
+                    message: format!($($arg)*),
+                }
+            }
+        }
+
+        macro_rules! budget_exceeded {
+            ($budget:expr) => {
+                InterpreterError::BudgetExceeded { budget: $budget, line_number, steps: machine.steps }
+            }
+        }
+
+        match instruction {
+            Instruction::Print(code) => {
+                let value = machine.eval(code).map_err(|e| runtime_error!("{e}"))?;
+                writeln!(self.program_output(), "{}", value)?;
+            },
+            Instruction::PrintText(text) => {
+                writeln!(self.program_output(), "{}", text)?;
+            },
+            Instruction::Read(place) => {
+                let value = machine.program.data.get(machine.data_position).
+                    cloned().
+                    ok_or_else(|| runtime_error!("Out of DATA"))?;
+
+                if value.value_type() != variable_type(place_name(&machine.program, place)) {
+                    return Err(runtime_error!("{}", EvalError::TypeMismatch));
+                }
+
+                machine.data_position += 1;
+                machine.store(place, value).map_err(|e| runtime_error!("{e}"))?;
+            },
+            Instruction::Input { prompt, place } => {
+                let value_type = variable_type(place_name(&machine.program, place));
+
+                let value = loop {
+                    if let Some(prompt) = prompt.as_ref().filter(|_| !machine.awaiting_input) {
+                        write!(self.program_output(), "{prompt}")?;
+                        self.program_output().flush()?;
+                    }
+                    machine.awaiting_input = false;
+
+                    if self.limits.max_inputs.is_some_and(|max| machine.inputs >= max) {
+                        return Err(budget_exceeded!(Budget::Inputs));
+                    }
+
+                    let Some(user_input) = self.read_input_line()? else {
+                        if self.suspend_on_input {
+                            // Run this instruction again once there's input.
+                            machine.awaiting_input = true;
+                            machine.pc -= 1;
+                            return Ok(true);
+                        }
+                        return Err(runtime_error!("No more input"));
+                    };
+                    machine.inputs += 1;
+
+                    match value_type {
+                        ValueType::Str => break Value::Str(user_input.trim_end_matches(['\r', '\n']).to_owned()),
+                        ValueType::Number => {
+                            let user_input = user_input.trim();
+                            match parse_number(user_input) {
+                                Some(number) => break number,
+                                None => writeln!(self.program_output(), "Not a number: {user_input}, try again")?,
+                            }
+                        },
+                    }
+                };
+
+                machine.store(place, value).map_err(|e| runtime_error!("{e}"))?;
+            },
+            Instruction::Extension { extension, arguments } => {
+                machine.store_variables(&mut self.variables);
+                let mut output = ProgramOutput { output: &mut *self.output, recording: self.recording.as_mut() };
+                extension.0.execute(arguments, &mut self.variables, &mut output).
+                    map_err(|e| runtime_error!("{e}"))?;
+                machine.load_variables(&self.variables).map_err(|e| runtime_error!("{e}"))?;
+            },
+            Instruction::Data | Instruction::Rem => {},
+            Instruction::Restore => {
+                machine.data_position = 0;
+            },
+            Instruction::Let(place, code) => {
+                let value = machine.eval(code).map_err(|e| runtime_error!("{e}"))?;
+                machine.store(place, value).map_err(|e| runtime_error!("{e}"))?;
+            },
+            Instruction::Dim(arrays) => {
+                for (array, bounds) in arrays {
+                    let bounds = machine.eval_indices(bounds).map_err(|e| runtime_error!("{e}"))?;
+                    machine.dim(*array, bounds).map_err(|e| runtime_error!("{e}"))?;
+                }
+            },
+            Instruction::Jump(target) => {
+                machine.pc = *target;
+            },
+            Instruction::Gosub(target) => {
+                if machine.call_stack.len() >= self.max_call_depth {
+                    return Err(runtime_error!("Stack overflow: GOSUB nested deeper than {}", self.max_call_depth));
+                }
+
+                machine.call_stack.push(machine.pc);
+                machine.pc = *target;
+            },
+            Instruction::Return => {
+                machine.pc = machine.call_stack.pop().
+                    ok_or_else(|| runtime_error!("RETURN without GOSUB"))?;
+            },
+            Instruction::For { slot, start, end, step, exit } => {
+                let start = machine.eval_number(start).map_err(|e| runtime_error!("{e}"))?;
+                let end = machine.eval_number(end).map_err(|e| runtime_error!("{e}"))?;
+                let step = match step {
+                    Some(step) => machine.eval_number(step).map_err(|e| runtime_error!("{e}"))?,
+                    None => Value::Int(1),
+                };
+
+                if !step.is_true() {
+                    return Err(runtime_error!("STEP must not be zero"));
+                }
+
+                let counts_up = step.compare(&Value::Int(0)).map_err(|e| runtime_error!("{e}"))?.is_gt();
+                let ordering = start.compare(&end).map_err(|e| runtime_error!("{e}"))?;
+                machine.slots[*slot] = Some(start);
+
+                if let Some(index) = machine.loop_stack.iter().position(|l| l.slot == *slot) {
+                    machine.loop_stack.truncate(index);
+                }
+
+                if (counts_up && ordering.is_gt()) || (!counts_up && ordering.is_lt()) {
+                    let var_name = &machine.program.slot_names[*slot];
+                    machine.pc = exit.ok_or_else(|| runtime_error!("FOR {var_name} without matching NEXT"))?;
+                } else {
+                    machine.loop_stack.push(ForLoop { slot: *slot, end, step, body: machine.pc });
+                }
+            },
+            Instruction::Next(slot) => {
+                let for_loop = machine.loop_stack.last().
+                    ok_or_else(|| runtime_error!("NEXT without FOR"))?;
+                let loop_var_name = &machine.program.slot_names[for_loop.slot];
+
+                if let Some(slot) = slot {
+                    if *slot != for_loop.slot {
+                        let var_name = &machine.program.slot_names[*slot];
+                        return Err(runtime_error!("NEXT {var_name} does not match FOR {loop_var_name}"));
+                    }
+                }
+
+                let value = machine.slots[for_loop.slot].clone().
+                    ok_or_else(|| runtime_error!("Unknown variable: {loop_var_name}"))?;
+                let counts_up = for_loop.step.compare(&Value::Int(0)).map_err(|e| runtime_error!("{e}"))?.is_gt();
+
+                // Overflowing past the end of the integer range just means the loop is over.
+                let next_value = match value.arithmetic(BinaryOp::Add, for_loop.step.clone()) {
+                    Err(EvalError::Overflow) => None,
+                    result => Some(result.map_err(|e| runtime_error!("{e}"))?),
+                };
+                let is_done = match &next_value {
+                    Some(next_value) => {
+                        let ordering = next_value.compare(&for_loop.end).map_err(|e| runtime_error!("{e}"))?;
+                        (counts_up && ordering.is_gt()) || (!counts_up && ordering.is_lt())
+                    },
+                    None => true,
+                };
+
+                if is_done {
+                    machine.loop_stack.pop();
+                } else {
+                    machine.slots[for_loop.slot] = next_value;
+                    machine.pc = for_loop.body;
+                }
+            },
+            Instruction::If { condition, then } => {
+                let value = machine.eval(condition).map_err(|e| runtime_error!("{e}"))?;
+
+                if value.is_true() {
+                    return self.execute_instruction(machine, then, line_number);
+                }
+            },
+            Instruction::Stop => {
+                machine.stopped_at = Some(line_number);
+            },
+            Instruction::Fail(message) => {
+                return Err(runtime_error!("{message}"));
+            },
+            Instruction::Halt => {
+                machine.pc -= 1;
+                return Ok(false);
+            },
+        }
+
+        Ok(true)
+    }
+
+    /// The next line queued by `feed_input`, or else the next line of the input reader unless the
+    /// program is suspended instead. While replaying, only the lines of the transcript are read.
+    fn read_input_line(&mut self) -> Result<Option<String>, InterpreterError> {
+        let line = match self.recording.as_mut().and_then(|r| r.replay_input.as_mut()) {
+            Some(replay_input) => replay_input.pop_front(),
+            None => match self.fed_input.pop_front() {
+                Some(line) => Some(line),
+                None if self.suspend_on_input => None,
+                None => {
+                    let mut line = String::new();
+                    if self.input.read_line(&mut line)? == 0 { None } else { Some(line) }
+                },
+            },
+        };
+
+        if let (Some(recording), Some(line)) = (&mut self.recording, &line) {
+            recording.input(line);
+        }
+        Ok(line)
+    }
+
+    fn program_output(&mut self) -> ProgramOutput<'_, W> {
+        ProgramOutput { output: &mut *self.output, recording: self.recording.as_mut() }
+    }
+
+    pub fn eval_value(&self, value: &str) -> Result<Value, InterpreterError> {
+        if value.starts_with(char::is_uppercase) {
+            self.variables.get(value).
+                cloned().
+                ok_or_else(|| InterpreterError::UnknownVariable { name: value.to_string() })
+        } else {
+            parse_number(value.trim()).
+                ok_or_else(|| InterpreterError::NotANumber { value: value.to_string() })
+        }
+    }
+}
+
+fn parse_code_line(input: &str, extensions: &Extensions) -> Result<(u16, Statement), LineError> {
+    let words = words(input);
+    let parts = words.iter().map(|(_, word)| *word).collect::<Vec<_>>();
+    let word_span = |index: usize| words.get(index).map_or(input.len()..input.len(), |(span, _)| span.clone());
+    let expect_words = |count: usize| match words.get(count) {
+        Some((span, _)) => Err(LineError::new(span.clone(), "expected end of line")),
+        None => Ok(()),
+    };
+
+    let line_number = parts.first().
+        and_then(|word| word.parse().ok()).
+        ok_or_else(|| LineError::new(word_span(0), "expected line number"))?;
+    let arguments = statement_arguments(input);
+    let base = input.len() - arguments.len();
+
+    // Comments are kept as written, so nothing after REM or ' is parsed.
+    match words.get(1) {
+        Some((span, "REM")) => {
+            // Only the separator after REM is dropped, so the comment keeps its spacing.
+            let text = &input[span.end..];
+            let text = text.strip_prefix(char::is_whitespace).unwrap_or(text).trim_end().to_owned();
+            return Ok((line_number, Statement::Rem { text, apostrophe: false }));
+        },
+        Some((span, word)) if word.starts_with('\'') => {
+            let text = input[span.start + 1..].trim_end().to_owned();
+            return Ok((line_number, Statement::Rem { text, apostrophe: true }));
+        },
+        _ => {},
+    }
+
+    // Expressions are parsed and evaluated recursively, so their size has to be bounded.
+    if let Some((span, _)) = tokenize(arguments).get(MAX_LINE_TOKENS) {
+        let message = format!("more than {MAX_LINE_TOKENS} tokens in line");
+        return Err(LineError::new(span.start + base..input.len(), message));
+    }
+    let functions = &extensions.functions;
+
+    let new_parser = || ExprParser::new(arguments, functions);
+    let parser_error = |parser: &ExprParser| {
+        let error = parser.error();
+        LineError::new(error.span.start + base..error.span.end + base, error.message)
+    };
+    let type_error = |parser: &ExprParser, start: usize, expected: Option<ValueType>| {
+        let span = parser.span_from(start);
+        let message = match expected {
+            Some(ValueType::Number) => "expected number expression",
+            Some(ValueType::Str) => "expected string expression",
+            None => "type mismatch in expression",
+        };
+        LineError::new(span.start + base..span.end + base, message)
+    };
+
+    let statement =
+        match parts.get(1) {
+            Some(&"PRINT") => {
+                let mut parser = new_parser();
+                let expr = parser.parse_to_end();
+
+                let value = match expr {
+                    Some(expr) if expr.value_type().is_some() => PrintValue::Expr(expr),
+                    _ if parts.len() == 3 => PrintValue::Text(parts[2].to_string()),
+                    Some(_) => return Err(type_error(&parser, 0, None)),
+                    None => return Err(parser_error(&parser)),
+                };
+                Statement::Print { value }
+            },
+            Some(&"LET") => {
+                let mut parser = new_parser();
+
+                let target = parser.parse_target().ok_or_else(|| parser_error(&parser))?;
+                parser.expect_symbol("=").ok_or_else(|| parser_error(&parser))?;
+                let value_start = parser.position;
+                let value = parser.parse_to_end().ok_or_else(|| parser_error(&parser))?;
+
+                if value.value_type() != Some(target.value_type()) {
+                    let expected = Some(target.value_type()).filter(|_| value.value_type().is_some());
+                    return Err(type_error(&parser, value_start, expected));
+                }
+
+                Statement::Let { target, value }
+            },
+            Some(&"READ") => {
+                let mut parser = new_parser();
+
+                let target = parser.parse_target().ok_or_else(|| parser_error(&parser))?;
+                parser.expect_end().ok_or_else(|| parser_error(&parser))?;
+
+                Statement::Read { target }
+            },
+            Some(&"INPUT") => {
+                let mut parser = new_parser();
+
+                let prompt = match parser.peek() {
+                    Some(Token::Str(_)) => {
+                        let Some(Token::Str(prompt)) = parser.next() else { unreachable!() };
+                        parser.expect_symbol(";").ok_or_else(|| parser_error(&parser))?;
+                        Some(prompt)
+                    },
+                    _ => None,
+                };
+
+                let target = parser.parse_target().ok_or_else(|| parser_error(&parser))?;
+                parser.expect_end().ok_or_else(|| parser_error(&parser))?;
+
+                Statement::Input { prompt, target }
+            },
+            Some(&"DATA") => {
+                let mut parser = new_parser();
+                let mut values = Vec::new();
+
+                loop {
+                    let is_negative = parser.peek() == Some(&Token::Symbol("-"));
+                    if is_negative {
+                        parser.next();
+                    }
+                    let value = match parser.peek().cloned() {
+                        Some(Token::Int(number)) if is_negative => Value::Int(-number),
+                        Some(Token::Float(number)) if is_negative => Value::Float(-number),
+                        Some(Token::Int(number)) => Value::Int(number),
+                        Some(Token::Float(number)) => Value::Float(number),
+                        Some(Token::Str(string)) if !is_negative => Value::Str(string),
+                        _ if is_negative => {
+                            parser.expected(parser.position, "number");
+                            return Err(parser_error(&parser));
+                        },
+                        _ => {
+                            parser.expected(parser.position, "number or string");
+                            return Err(parser_error(&parser));
+                        },
+                    };
+                    parser.next();
+                    values.push(value);
+
+                    if parser.peek().is_none() {
+                        break;
+                    }
+                    parser.expect_symbol(",").ok_or_else(|| parser_error(&parser))?;
+                }
+
+                Statement::Data { values }
+            },
+            Some(&"RESTORE") => {
+                expect_words(2)?;
+                Statement::Restore
+            },
+            Some(&"DIM") => {
+                let mut parser = new_parser();
+                let mut arrays = Vec::new();
+
+                loop {
+                    match parser.parse_target() {
+                        Some(Target::Element { name, indices }) => arrays.push((name, indices)),
+                        Some(Target::Variable(_)) => {
+                            parser.expected(parser.position, "\"(\"");
+                            return Err(parser_error(&parser));
+                        },
+                        None => return Err(parser_error(&parser)),
+                    }
+
+                    if parser.peek().is_none() {
+                        break;
+                    }
+                    parser.expect_symbol(",").ok_or_else(|| parser_error(&parser))?;
+                }
+
+                Statement::Dim { arrays }
+            },
+            Some(&"GOTO") | Some(&"GOSUB") => {
+                let line_number = parts.get(2).
+                    and_then(|word| word.parse().ok()).
+                    ok_or_else(|| LineError::new(word_span(2), "expected line number"))?;
+                expect_words(3)?;
+
+                if parts[1] == "GOTO" {
+                    Statement::Goto { line_number }
+                } else {
+                    Statement::Gosub { line_number }
+                }
+            },
+            Some(&"RETURN") => {
+                expect_words(2)?;
+                Statement::Return
+            },
+            Some(&"END") => {
+                expect_words(2)?;
+                Statement::End
+            },
+            Some(&"STOP") => {
+                expect_words(2)?;
+                Statement::Stop
+            },
+            Some(&"FOR") => {
+                let mut parser = new_parser();
+
+                let var_name = parser.parse_name().ok_or_else(|| parser_error(&parser))?;
+                if variable_type(&var_name) != ValueType::Number {
+                    return Err(LineError::new(word_span(2), "expected number variable"));
+                }
+                parser.expect_symbol("=").ok_or_else(|| parser_error(&parser))?;
+
+                let parse_number_expr = |parser: &mut ExprParser, to_end: bool| {
+                    let start = parser.position;
+                    let expr = if to_end { parser.parse_to_end() } else { parser.parse_expr() };
+                    let expr = expr.ok_or_else(|| parser_error(parser))?;
+
+                    if expr.value_type() != Some(ValueType::Number) {
+                        let expected = expr.value_type().map(|_| ValueType::Number);
+                        return Err(type_error(parser, start, expected));
+                    }
+                    Ok(expr)
+                };
+
+                let start = parse_number_expr(&mut parser, false)?;
+                parser.expect_keyword("TO").ok_or_else(|| parser_error(&parser))?;
+                let end = parse_number_expr(&mut parser, false)?;
+
+                let step = if parser.peek().is_some() {
+                    parser.expect_keyword("STEP").ok_or_else(|| parser_error(&parser))?;
+                    Some(parse_number_expr(&mut parser, true)?)
+                } else {
+                    None
+                };
+
+                Statement::For { var_name, start, end, step }
+            },
+            Some(&"NEXT") => {
+                let var_name = parts.get(2).map(|name| name.to_string());
+                if let Some(name) = &var_name {
+                    if !name.starts_with(char::is_uppercase) || variable_type(name) != ValueType::Number {
+                        return Err(LineError::new(word_span(2), "expected number variable"));
+                    }
+                }
+                expect_words(3)?;
+                Statement::Next { var_name }
+            },
+            Some(&"IF") => {
+                let mut parser = new_parser();
+                let condition = parser.parse_condition().ok_or_else(|| parser_error(&parser))?;
+                let keyword_position = parser.position;
+
+                let then = match (parser.next(), parser.next(), parser.peek()) {
+                    (Some(Token::Name(keyword)), Some(Token::Int(target)), None)
+                        if keyword == "GOTO" || keyword == "THEN" => {
+                        let line_number = u16::try_from(target).map_err(|_| {
+                            let span = parser.span_from(keyword_position + 1);
+                            LineError::new(span.start + base..span.end + base, "expected line number")
+                        })?;
+                        Statement::Goto { line_number }
+                    },
+                    (Some(Token::Name(keyword)), Some(_), _) if keyword == "THEN" => {
+                        parser.position -= 1;
+                        let offset = base + parser.span_from(parser.position).start;
+                        let prefix = format!("{line_number} ");
+                        let code = format!("{prefix}{}", parser.remaining_input());
+
+                        // Errors in the statement after THEN are reported relative to this line.
+                        parse_code_line(&code, extensions).
+                            map_err(|e| {
+                                let start = e.span.start.saturating_sub(prefix.len()) + offset;
+                                let end = e.span.end.saturating_sub(prefix.len()) + offset;
+                                LineError::new(start..end, e.message)
+                            })?.
+                            1
+                    },
+                    (Some(Token::Name(keyword)), _, _) if keyword == "THEN" || keyword == "GOTO" => {
+                        parser.expected(keyword_position + 1, if keyword == "THEN" { "statement" } else { "line number" });
+                        return Err(parser_error(&parser));
+                    },
+                    _ => {
+                        parser.expected(keyword_position, "THEN");
+                        parser.expected(keyword_position, "GOTO");
+                        return Err(parser_error(&parser));
+                    },
+                };
+
+                Statement::If { condition, then: Box::new(then) }
+            },
+            Some(keyword) if extensions.statements.contains_key(*keyword) => {
+                let extension = extensions.statements[*keyword].clone();
+                let arguments = extension.0.parse(arguments).ok_or_else(|| {
+                    let span = if arguments.is_empty() { word_span(1) } else { base..input.len() };
+                    LineError::new(span, format!("invalid arguments for {keyword}"))
+                })?;
+
+                Statement::Extension { keyword: keyword.to_string(), arguments, extension }
+            },
+            _ => return Err(LineError::new(word_span(1), "expected statement")),
+        };
+
+    Ok((line_number, statement))
+}
+
+/// The whitespace-separated words of the input, with their byte ranges.
+fn words(input: &str) -> Vec<(Range<usize>, &str)> {
+    let mut words = Vec::new();
+    let mut word_start = None;
+
+    for (offset, c) in input.char_indices().chain([(input.len(), ' ')]) {
+        match (word_start, c.is_whitespace()) {
+            (None, false) => word_start = Some(offset),
+            (Some(start), true) => {
+                words.push((start..offset, &input[start..offset]));
+                word_start = None;
+            },
+            _ => {},
+        }
+    }
+
+    words
+}
+
+/// The raw text following the line number and keyword. Expressions are tokenized from this
+/// rather than from whitespace-separated parts, so spacing inside string literals survives.
+fn statement_arguments(input: &str) -> &str {
+    let mut rest = input.trim_start();
+
+    for _ in 0..2 {
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[word_end..].trim_start();
+    }
+
+    rest
+}
+
+/// Numbers as they're printed, strings quoted with `\`, `"` and newlines escaped.
+fn fmt_snapshot_value(value: &Value) -> String {
+    match value {
+        Value::Str(string) => {
+            let escaped = string.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("\"{escaped}\"")
+        },
+        number => number.to_string(),
+    }
+}
+
+/// Parses a comma-separated list of values written by `fmt_snapshot_value`.
+fn parse_snapshot_values(text: &str) -> Option<Vec<Value>> {
+    let mut values = Vec::new();
+    let mut chars = text.trim().chars().peekable();
+
+    while chars.peek().is_some() {
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let mut string = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' if chars.next_if_eq(&'n').is_some() => string.push('\n'),
+                    '\\' => string.push(chars.next()?),
+                    c => string.push(c),
+                }
+            }
+            Value::Str(string)
+        } else {
+            let mut number = String::new();
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                number.push(c);
+            }
+            parse_number(number.trim())?
+        };
+        values.push(value);
+
+        while chars.next_if_eq(&' ').is_some() {}
+        match chars.next() {
+            Some(',') => while chars.next_if_eq(&' ').is_some() {},
+            None => break,
+            _ => return None,
+        }
+    }
+
+    Some(values)
+}
+
+/// Restores one line of session state written by `Interpreter::snapshot`, checking that it fits
+/// the compiled program so that the machine can't panic later.
+fn restore_machine_line(machine: &mut Machine, line: &str) -> Option<()> {
+    let (tag, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let instruction_count = machine.program.instructions.len();
+
+    match tag {
+        "PC" => machine.pc = rest.parse().ok().filter(|pc| *pc < instruction_count)?,
+        "CALL" => machine.call_stack.push(rest.parse().ok().filter(|pc| *pc < instruction_count)?),
+        "LOOP" => {
+            let mut parts = rest.splitn(3, ' ');
+            let name = parts.next()?;
+            let slot = machine.program.slot_names.iter().position(|n| n == name)?;
+            let body = parts.next()?.parse().ok().filter(|pc| *pc < instruction_count)?;
+            let [end, step] = <[Value; 2]>::try_from(parse_snapshot_values(parts.next()?)?).ok()?;
+
+            if end.value_type() != ValueType::Number || step.value_type() != ValueType::Number {
+                return None;
+            }
+            machine.loop_stack.push(ForLoop { slot, end, step, body });
+        },
+        "ARRAY" => {
+            let (header, values) = rest.split_once(" = ")?;
+            let (name, bounds) = header.split_once(' ')?;
+            let array = machine.program.array_names.iter().position(|n| n == name)?;
+            let bounds = bounds.split(',').map(|b| b.parse().ok()).collect::<Option<Vec<usize>>>()?;
+            let values = parse_snapshot_values(values)?;
+
+            let size = bounds.iter().try_fold(1usize, |size, bound| size.checked_mul(bound.checked_add(1)?));
+            if size != Some(values.len()) || values.iter().any(|v| v.value_type() != variable_type(name)) {
+                return None;
+            }
+            machine.arrays[array] = Some(Array { bounds, values });
+        },
+        "DATA" => machine.data_position = rest.parse().ok()?,
+        "RANDOM" => machine.random_state.set(rest.parse().ok()?),
+        "STEPS" => machine.steps = rest.parse().ok()?,
+        "INPUTS" => machine.inputs = rest.parse().ok()?,
+        "AWAITING" => machine.awaiting_input = true,
+        _ => return None,
+    }
+
+    Some(())
+}
+
+/// Whether a jump back from this instruction closes a loop. RETURN also jumps back, but to
+/// wherever the subroutine was called from.
+fn is_loop_jump(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Jump(_) | Instruction::Next(_) => true,
+        Instruction::If { then, .. } => is_loop_jump(then),
+        _ => false,
+    }
+}
+
+/// Whether executing this instruction may wait for input, see `Machine::awaiting_input`.
+fn reads_input(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Input { .. } => true,
+        Instruction::If { then, .. } => reads_input(then),
+        _ => false,
+    }
+}
+
+/// Finds the NEXT that closes the FOR loop on `for_line_number`, skipping over nested loops.
+fn find_matching_next(
+    code_lines: &HashMap<u16, Statement>,
+    line_numbers: &[u16],
+    for_line_number: u16,
+    var_name: &str,
+) -> Option<u16> {
+    let mut depth = 0;
+
+    for line_number in line_numbers.iter().filter(|n| **n > for_line_number) {
+        let statement = match code_lines.get(line_number)? {
+            Statement::If { then, .. } => then.as_ref(),
+            statement => statement,
+        };
+
+        match statement {
+            Statement::For { .. } => depth += 1,
+            Statement::Next { var_name: next_var } if depth == 0 => {
+                if next_var.as_deref().is_none_or(|name| name == var_name) {
+                    return Some(*line_number);
+                }
+                return None;
+            },
+            Statement::Next { .. } => depth -= 1,
+            _ => {},
+        }
+    }
+
+    None
+}
+
+struct Compiler<'a> {
+    code_lines: &'a HashMap<u16, Statement>,
+    known_functions: &'a HashMap<String, Function>,
+    line_numbers: Vec<u16>,
+    line_indices: HashMap<u16, usize>,
+    slots: HashMap<String, usize>,
+    slot_names: Vec<String>,
+    arrays: HashMap<String, usize>,
+    array_names: Vec<String>,
+    function_indices: HashMap<String, usize>,
+    functions: Vec<(String, Function)>,
+    data: Vec<Value>,
+    stubs: Vec<(Instruction, u16)>,
+}
+
+/// Compiles the program into bytecode. Jumps to missing lines are resolved to `Fail` stubs placed
+/// after the final `Halt`, so they still only fail if they're actually taken.
+fn compile(code_lines: &HashMap<u16, Statement>, functions: &HashMap<String, Function>) -> Program {
+    let mut line_numbers = code_lines.keys().cloned().collect::<Vec<_>>();
+    line_numbers.sort();
+
+    let line_indices = line_numbers.iter().enumerate().map(|(index, n)| (*n, index)).collect();
+    let mut compiler = Compiler {
+        code_lines,
+        known_functions: functions,
+        line_numbers,
+        line_indices,
+        slots: HashMap::new(),
+        slot_names: Vec::new(),
+        arrays: HashMap::new(),
+        array_names: Vec::new(),
+        function_indices: HashMap::new(),
+        functions: Vec::new(),
+        data: Vec::new(),
+        stubs: Vec::new(),
+    };
+
+    let mut instructions = Vec::with_capacity(compiler.line_numbers.len() + 1);
+    for index in 0..compiler.line_numbers.len() {
+        let line_number = compiler.line_numbers[index];
+        instructions.push(compiler.compile_statement(line_number, &code_lines[&line_number]));
+    }
+
+    let mut line_numbers = compiler.line_numbers;
+    instructions.push(Instruction::Halt);
+    line_numbers.push(line_numbers.last().copied().unwrap_or(0));
+
+    for (stub, line_number) in compiler.stubs {
+        instructions.push(stub);
+        line_numbers.push(line_number);
+    }
+
+    Program {
+        instructions,
+        line_numbers,
+        slot_names: compiler.slot_names,
+        array_names: compiler.array_names,
+        functions: compiler.functions,
+        data: compiler.data,
+    }
+}
+
+impl Compiler<'_> {
+    fn slot(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+
+        let slot = self.slot_names.len();
+        self.slots.insert(name.to_owned(), slot);
+        self.slot_names.push(name.to_owned());
+        slot
+    }
+
+    fn array(&mut self, name: &str) -> usize {
+        if let Some(array) = self.arrays.get(name) {
+            return *array;
+        }
+
+        let array = self.array_names.len();
+        self.arrays.insert(name.to_owned(), array);
+        self.array_names.push(name.to_owned());
+        array
+    }
+
+    fn function(&mut self, name: &str) -> usize {
+        if let Some(function) = self.function_indices.get(name) {
+            return *function;
+        }
+
+        let function = self.functions.len();
+        self.function_indices.insert(name.to_owned(), function);
+        self.functions.push((name.to_owned(), self.known_functions[name].clone()));
+        function
+    }
+
+    fn place(&mut self, target: &Target) -> Place {
+        match target {
+            Target::Variable(name) => Place::Slot(self.slot(name)),
+            Target::Element { name, indices } => {
+                Place::Element {
+                    array: self.array(name),
+                    indices: indices.iter().map(|index| self.compile_expr(index)).collect(),
+                }
+            },
+        }
+    }
+
+    fn jump_target(&mut self, target: u16, keyword: &str, line_number: u16) -> usize {
+        if let Some(index) = self.line_indices.get(&target) {
+            return *index;
+        }
+
+        let index = self.line_numbers.len() + 1 + self.stubs.len();
+        let message = format!("Invalid line number for {keyword}: {target}");
+        self.stubs.push((Instruction::Fail(message), line_number));
+        index
+    }
+
+    fn compile_statement(&mut self, line_number: u16, statement: &Statement) -> Instruction {
+        match statement {
+            Statement::Print { value: PrintValue::Expr(expr) } => Instruction::Print(self.compile_expr(expr)),
+            Statement::Print { value: PrintValue::Text(text) } => Instruction::PrintText(text.clone()),
+            Statement::Read { target } => Instruction::Read(self.place(target)),
+            Statement::Input { prompt, target } => {
+                Instruction::Input { prompt: prompt.clone(), place: self.place(target) }
+            },
+            Statement::Data { values } => {
+                self.data.extend(values.iter().cloned());
+                Instruction::Data
+            },
+            Statement::Restore => Instruction::Restore,
+            Statement::Rem { .. } => Instruction::Rem,
+            // The final `Halt` comes right after the last line.
+            Statement::End => Instruction::Jump(self.line_numbers.len()),
+            Statement::Stop => Instruction::Stop,
+            Statement::Extension { arguments, extension, .. } => {
+                Instruction::Extension { extension: extension.clone(), arguments: arguments.clone() }
+            },
+            Statement::Let { target, value } => {
+                Instruction::Let(self.place(target), self.compile_expr(value))
+            },
+            Statement::Dim { arrays } => {
+                let arrays = arrays.iter().
+                    map(|(name, bounds)| {
+                        (self.array(name), bounds.iter().map(|bound| self.compile_expr(bound)).collect())
+                    }).
+                    collect();
+                Instruction::Dim(arrays)
+            },
+            Statement::Goto { line_number: target } => {
+                Instruction::Jump(self.jump_target(*target, "GOTO", line_number))
+            },
+            Statement::Gosub { line_number: target } => {
+                Instruction::Gosub(self.jump_target(*target, "GOSUB", line_number))
+            },
+            Statement::Return => Instruction::Return,
+            Statement::For { var_name, start, end, step } => {
+                let exit = find_matching_next(self.code_lines, &self.line_numbers, line_number, var_name).
+                    map(|next_line_number| self.line_indices[&next_line_number] + 1);
+
+                Instruction::For {
+                    slot: self.slot(var_name),
+                    start: self.compile_expr(start),
+                    end: self.compile_expr(end),
+                    step: step.as_ref().map(|step| self.compile_expr(step)),
+                    exit,
+                }
+            },
+            Statement::Next { var_name } => Instruction::Next(var_name.as_ref().map(|name| self.slot(name))),
+            Statement::If { condition, then } => {
+                let mut code = Vec::new();
+                self.emit_condition(condition, &mut code);
+
+                Instruction::If { condition: code, then: Box::new(self.compile_statement(line_number, then)) }
+            },
+        }
+    }
+
+    fn emit_condition(&mut self, condition: &Condition, code: &mut Vec<Op>) {
+        match condition {
+            Condition::Compare { left, op, right } => {
+                self.emit_expr(left, code);
+                self.emit_expr(right, code);
+                code.push(Op::Compare(*op));
+            },
+            Condition::Not(inner) => {
+                self.emit_condition(inner, code);
+                code.push(Op::Not);
+            },
+            Condition::And(left, right) => {
+                self.emit_condition(left, code);
+                self.emit_condition(right, code);
+                code.push(Op::And);
+            },
+            Condition::Or(left, right) => {
+                self.emit_condition(left, code);
+                self.emit_condition(right, code);
+                code.push(Op::Or);
+            },
+        }
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> Vec<Op> {
+        let mut code = Vec::new();
+        self.emit_expr(expr, &mut code);
+        code
+    }
+
+    fn emit_expr(&mut self, expr: &Expr, code: &mut Vec<Op>) {
+        match expr {
+            Expr::Int(number) => code.push(Op::Push(Value::Int(*number))),
+            Expr::Float(number) => code.push(Op::Push(Value::Float(*number))),
+            Expr::Str(string) => code.push(Op::Push(Value::Str(string.clone()))),
+            Expr::Variable(name) => code.push(Op::Load(self.slot(name))),
+            Expr::Element { name, indices } => {
+                for index in indices {
+                    self.emit_expr(index, code);
+                }
+                code.push(Op::LoadElement { array: self.array(name), dimensions: indices.len() });
+            },
+            Expr::Call { name, arguments } => {
+                for argument in arguments {
+                    self.emit_expr(argument, code);
+                }
+                code.push(Op::Call { function: self.function(name), arguments: arguments.len() });
+            },
+            Expr::Negate(inner) => {
+                self.emit_expr(inner, code);
+                code.push(Op::Negate);
+            },
+            Expr::Binary { op, left, right } => {
+                self.emit_expr(left, code);
+                self.emit_expr(right, code);
+                code.push(Op::Binary(*op));
+            },
+        }
+    }
+}
+
+/// The shared runtime, which every transpiled program starts with after its header.
+const RUST_RUNTIME: &str = include_str!("basic_runtime.rs");
+
+/// The rest of every transpiled program that doesn't depend on the BASIC code: the machine state
+/// and statements, mirroring `Machine` and `Interpreter::execute`. `transpile` appends the
+/// program's names, data and one function per line, which return the line to continue with.
+const RUST_MACHINE: &str = r##"
+use std::io::{self, BufRead, Write};
+use std::process;
+
+#[derive(Debug)]
+enum Fault {
+    Runtime(String),
+    Stopped,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Fault {
+    fn from(source: io::Error) -> Self {
+        Self::Io(source)
+    }
+}
+
+impl From<EvalError> for Fault {
+    fn from(error: EvalError) -> Self {
+        Self::Runtime(error.to_string())
+    }
+}
+
+fn fault(message: impl Into<String>) -> Fault {
+    Fault::Runtime(message.into())
+}
+
+fn number(value: Value) -> Result<Value, Fault> {
+    match value {
+        Value::Str(_) => Err(EvalError::TypeMismatch.into()),
+        number => Ok(number),
+    }
+}
+
+fn compare(left: Value, op: CompareOp, right: Value) -> Result<Value, Fault> {
+    Ok(Value::Int(op.holds(left.compare(&right)?) as i64))
+}
+
+fn not(value: Value) -> Value {
+    Value::Int(!value.is_true() as i64)
+}
+
+fn and(left: Value, right: Value) -> Value {
+    Value::Int((left.is_true() && right.is_true()) as i64)
+}
+
+fn or(left: Value, right: Value) -> Value {
+    Value::Int((left.is_true() || right.is_true()) as i64)
+}
+
+struct ForLoop {
+    slot: usize,
+    end: Value,
+    step: Value,
+    body: Option<u16>,
+}
+
+/// Lines are functions returning the line to continue with, `None` once the program ends.
+struct Machine<R, W> {
+    slots: Vec<Option<Value>>,
+    arrays: Vec<Option<Array>>,
+    call_stack: Vec<Option<u16>>,
+    loop_stack: Vec<ForLoop>,
+    data: Vec<Value>,
+    data_position: usize,
+    random_state: Cell<u64>,
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Machine<R, W> {
+    fn load(&self, slot: usize) -> Result<Value, Fault> {
+        Ok(self.slots[slot].clone().ok_or_else(|| EvalError::UnknownVariable(SLOT_NAMES[slot].to_owned()))?)
+    }
+
+    fn dim(&mut self, array: usize, bounds: Vec<i64>) -> Result<(), Fault> {
+        self.arrays[array] = Some(Array::new(ARRAY_NAMES[array], bounds)?);
+        Ok(())
+    }
+
+    fn element_index(&self, array: usize, indices: &[i64]) -> Result<usize, Fault> {
+        let name = ARRAY_NAMES[array];
+        let array = self.arrays[array].as_ref().ok_or_else(|| EvalError::UnknownArray(name.to_owned()))?;
+        Ok(array.position(name, indices)?)
+    }
+
+    fn load_element(&self, array: usize, indices: Vec<Value>) -> Result<Value, Fault> {
+        let indices = indices.iter().map(Value::as_index).collect::<Result<Vec<_>, _>>()?;
+        let position = self.element_index(array, &indices)?;
+        Ok(self.arrays[array].as_ref().expect("element_index checks the array exists").values[position].clone())
+    }
+
+    fn store_element(&mut self, array: usize, indices: Vec<i64>, value: Value) -> Result<(), Fault> {
+        let position = self.element_index(array, &indices)?;
+        if let Some(array) = &mut self.arrays[array] {
+            array.values[position] = value;
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, is_string: bool) -> Result<Value, Fault> {
+        let value = self.data.get(self.data_position).cloned().ok_or_else(|| fault("Out of DATA"))?;
+        if (value.value_type() == ValueType::Str) != is_string {
+            return Err(EvalError::TypeMismatch.into());
+        }
+
+        self.data_position += 1;
+        Ok(value)
+    }
+
+    fn input(&mut self, prompt: Option<&str>, is_string: bool) -> Result<Value, Fault> {
+        loop {
+            if let Some(prompt) = prompt {
+                write!(self.output, "{prompt}")?;
+                self.output.flush()?;
+            }
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Err(fault("No more input"));
+            }
+
+            if is_string {
+                return Ok(Value::Str(line.trim_end_matches(['\r', '\n']).to_owned()));
+            }
+            let line = line.trim();
+            match parse_number(line) {
+                Some(number) => return Ok(number),
+                None => writeln!(self.output, "Not a number: {line}, try again")?,
+            }
+        }
+    }
+
+    fn gosub(&mut self, return_to: Option<u16>) -> Result<(), Fault> {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err(fault(format!("Stack overflow: GOSUB nested deeper than {MAX_CALL_DEPTH}")));
+        }
+
+        self.call_stack.push(return_to);
+        Ok(())
+    }
+
+    fn return_from_gosub(&mut self) -> Result<Option<u16>, Fault> {
+        self.call_stack.pop().ok_or_else(|| fault("RETURN without GOSUB"))
+    }
+
+    /// `exit` is the line after the matching NEXT, or `None` if there is no matching NEXT.
+    fn start_for(
+        &mut self,
+        slot: usize,
+        start: Value,
+        end: Value,
+        step: Value,
+        body: Option<u16>,
+        exit: Option<Option<u16>>,
+    ) -> Result<Option<u16>, Fault> {
+        if !step.is_true() {
+            return Err(fault("STEP must not be zero"));
+        }
+
+        let counts_up = step.compare(&Value::Int(0))?.is_gt();
+        let order = start.compare(&end)?;
+        self.slots[slot] = Some(start);
+
+        if let Some(index) = self.loop_stack.iter().position(|l| l.slot == slot) {
+            self.loop_stack.truncate(index);
+        }
+
+        if (counts_up && order.is_gt()) || (!counts_up && order.is_lt()) {
+            exit.ok_or_else(|| fault(format!("FOR {} without matching NEXT", SLOT_NAMES[slot])))
+        } else {
+            self.loop_stack.push(ForLoop { slot, end, step, body });
+            Ok(body)
+        }
+    }
+
+    fn next(&mut self, slot: Option<usize>, after: Option<u16>) -> Result<Option<u16>, Fault> {
+        let for_loop = self.loop_stack.last().ok_or_else(|| fault("NEXT without FOR"))?;
+        let loop_var_name = SLOT_NAMES[for_loop.slot];
+
+        if let Some(slot) = slot {
+            if slot != for_loop.slot {
+                return Err(fault(format!("NEXT {} does not match FOR {loop_var_name}", SLOT_NAMES[slot])));
+            }
+        }
+
+        let value = self.slots[for_loop.slot].clone().
+            ok_or_else(|| EvalError::UnknownVariable(loop_var_name.to_owned()))?;
+        let counts_up = for_loop.step.compare(&Value::Int(0))?.is_gt();
+
+        // Overflowing past the end of the integer range just means the loop is over.
+        let next_value = match value.arithmetic(BinaryOp::Add, for_loop.step.clone()) {
+            Err(EvalError::Overflow) => None,
+            result => Some(result?),
+        };
+        let is_done = match &next_value {
+            Some(next_value) => {
+                let order = next_value.compare(&for_loop.end)?;
+                (counts_up && order.is_gt()) || (!counts_up && order.is_lt())
+            },
+            None => true,
+        };
+
+        if is_done {
+            self.loop_stack.pop();
+            Ok(after)
+        } else {
+            let body = for_loop.body;
+            self.slots[for_loop.slot] = next_value;
+            Ok(body)
+        }
+    }
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut machine = Machine {
+        slots: initial_slots(),
+        arrays: ARRAY_NAMES.iter().map(|_| None).collect(),
+        call_stack: Vec::new(),
+        loop_stack: Vec::new(),
+        data: data(),
+        data_position: 0,
+        random_state: Cell::new(SEED),
+        input: stdin.lock(),
+        output: io::BufWriter::new(stdout.lock()),
+    };
+
+    let result = run(&mut machine);
+    let flushed = machine.output.flush();
+
+    let message = match (result, flushed) {
+        (Err((line_number, Fault::Runtime(message))), _) => {
+            format!("Runtime error in line {line_number}: {message}")
+        },
+        (Err((line_number, Fault::Stopped)), _) => format!("Stopped in line {line_number}"),
+        (Err((_, Fault::Io(e))), _) | (Ok(()), Err(e)) => format!("I/O error: {e}"),
+        (Ok(()), Ok(())) => return,
+    };
+    eprintln!("{message}");
+    process::exit(1);
+}
+"##;
+
+/// A value as a Rust expression for the transpiled program. NaN and the infinities have no
+/// literal, so they can't be transpiled.
+fn rust_value(value: &Value) -> Result<String, String> {
+    match value {
+        Value::Int(number) => Ok(format!("Value::Int({number})")),
+        Value::Float(number) if !number.is_finite() => Err(format!("{number} isn't a finite number")),
+        Value::Float(number) => Ok(format!("Value::Float({number:?})")),
+        Value::Str(string) => Ok(format!("Value::Str({string:?}.to_owned())")),
+    }
+}
+
+/// Generates the Rust code of each line from its compiled instruction, so the transpiled
+/// program evaluates in exactly the same order as `Machine`.
+struct Transpiler<'a> {
+    program: &'a Program,
+    /// How many instructions are lines; the `Halt` after them ends the program.
+    line_count: usize,
+}
+
+impl Transpiler<'_> {
+    /// The line execution continues with at an instruction index, as a Rust `Option<u16>`.
+    fn state(&self, index: usize) -> String {
+        if index < self.line_count {
+            format!("Some({})", self.program.line_numbers[index])
+        } else {
+            "None".to_owned()
+        }
+    }
+
+    /// A Rust statement jumping to an instruction index, which may be a `Fail` stub.
+    fn jump(&self, index: usize) -> String {
+        match &self.program.instructions[index] {
+            Instruction::Fail(message) => format!("return Err(fault({message:?}));"),
+            _ => format!("return Ok({});", self.state(index)),
+        }
+    }
+
+    /// Turns postfix expression code back into a nested Rust expression evaluating to a `Value`.
+    fn expr(&self, code: &[Op]) -> Result<String, String> {
+        let mut stack = Vec::with_capacity(code.len());
+
+        for op in code {
+            let expr = match op {
+                Op::Push(value) => rust_value(value)?,
+                Op::Load(slot) => format!("m.load({slot})?"),
+                Op::LoadElement { array, dimensions } => {
+                    let indices = stack.split_off(stack.len() - dimensions);
+                    format!("m.load_element({array}, vec![{}])?", indices.join(", "))
+                },
+                Op::Call { function, arguments } => {
+                    let arguments = stack.split_off(stack.len() - arguments);
+                    match &self.program.functions[*function] {
+                        (name, Function::Builtin(builtin)) => {
+                            format!("call_builtin({name:?}, Builtin::{builtin:?}, &[{}], &m.random_state)?", arguments.join(", "))
+                        },
+                        (name, Function::Native(_)) => return Err(format!("{name} is a native function")),
+                    }
+                },
+                Op::Negate => format!("{}.negate()?", stack.pop().expect("expression code is balanced")),
+                Op::Binary(op) => {
+                    let right = stack.pop().expect("expression code is balanced");
+                    let left = stack.pop().expect("expression code is balanced");
+                    format!("{left}.arithmetic(BinaryOp::{op:?}, {right})?")
+                },
+                Op::Compare(op) => {
+                    let right = stack.pop().expect("expression code is balanced");
+                    let left = stack.pop().expect("expression code is balanced");
+                    format!("compare({left}, CompareOp::{op:?}, {right})?")
+                },
+                Op::Not => format!("not({})", stack.pop().expect("expression code is balanced")),
+                Op::And | Op::Or => {
+                    let right = stack.pop().expect("expression code is balanced");
+                    let left = stack.pop().expect("expression code is balanced");
+                    let function = if matches!(op, Op::And) { "and" } else { "or" };
+                    format!("{function}({left}, {right})")
+                },
+            };
+            stack.push(expr);
+        }
+
+        Ok(stack.pop().expect("expression code is balanced"))
+    }
+
+    fn indices(&self, indices: &[Vec<Op>]) -> Result<String, String> {
+        let indices = indices.iter().
+            map(|code| Ok(format!("{}.as_index()?", self.expr(code)?))).
+            collect::<Result<Vec<_>, String>>()?;
+        Ok(format!("vec![{}]", indices.join(", ")))
+    }
+
+    /// Stores the Rust variable `value` into a place.
+    fn store(&self, place: &Place) -> Result<String, String> {
+        match place {
+            Place::Slot(slot) => Ok(format!("m.slots[{slot}] = Some(value);")),
+            Place::Element { array, indices } => {
+                Ok(format!("m.store_element({array}, {}, value)?;", self.indices(indices)?))
+            },
+        }
+    }
+
+    /// The body of the function for the line at `index`. Falling off its end continues with the
+    /// next line.
+    fn statement(&self, instruction: &Instruction, index: usize) -> Result<Vec<String>, String> {
+        let next = self.state(index + 1);
+        let is_string = |place: &Place| variable_type(place_name(self.program, place)) == ValueType::Str;
+
+        let lines = match instruction {
+            Instruction::Print(code) => {
+                vec![format!("let value = {};", self.expr(code)?), "writeln!(m.output, \"{value}\")?;".to_owned()]
+            },
+            Instruction::PrintText(text) => vec![format!("writeln!(m.output, \"{{}}\", {text:?})?;")],
+            Instruction::Read(place) => {
+                vec![format!("let value = m.read_data({})?;", is_string(place)), self.store(place)?]
+            },
+            Instruction::Input { prompt, place } => {
+                let prompt = prompt.as_deref();
+                vec![format!("let value = m.input({prompt:?}, {})?;", is_string(place)), self.store(place)?]
+            },
+            Instruction::Extension { .. } => {
+                return Err("extension statements only exist in the interpreter".to_owned());
+            },
+            Instruction::Data | Instruction::Rem => Vec::new(),
+            Instruction::Restore => vec!["m.data_position = 0;".to_owned()],
+            Instruction::Let(place, code) => {
+                vec![format!("let value = {};", self.expr(code)?), self.store(place)?]
+            },
+            Instruction::Dim(arrays) => {
+                arrays.iter().
+                    map(|(array, bounds)| Ok(format!("m.dim({array}, {})?;", self.indices(bounds)?))).
+                    collect::<Result<_, String>>()?
+            },
+            Instruction::Jump(target) => vec![self.jump(*target)],
+            Instruction::Gosub(target) => vec![format!("m.gosub({next})?;"), self.jump(*target)],
+            Instruction::Return => vec!["return m.return_from_gosub();".to_owned()],
+            Instruction::For { slot, start, end, step, exit } => {
+                let step = match step {
+                    Some(step) => format!("number({})?", self.expr(step)?),
+                    None => "Value::Int(1)".to_owned(),
+                };
+                let exit = match exit {
+                    Some(exit) => format!("Some({})", self.state(*exit)),
+                    None => "None".to_owned(),
+                };
+
+                vec![
+                    format!("let start = number({})?;", self.expr(start)?),
+                    format!("let end = number({})?;", self.expr(end)?),
+                    format!("let step = {step};"),
+                    format!("return m.start_for({slot}, start, end, step, {next}, {exit});"),
+                ]
+            },
+            Instruction::Next(slot) => vec![format!("return m.next({slot:?}, {next});")],
+            Instruction::If { condition, then } => {
+                let mut lines = vec![format!("if {}.is_true() {{", self.expr(condition)?)];
+                lines.extend(self.statement(then, index)?.into_iter().map(|line| format!("    {line}")));
+                lines.push("}".to_owned());
+                lines
+            },
+            Instruction::Stop => vec!["return Err(Fault::Stopped);".to_owned()],
+            Instruction::Fail(_) | Instruction::Halt => unreachable!("only lines are transpiled"),
+        };
+
+        Ok(lines)
+    }
+}
+
+/// Generates a standalone Rust program running `program`, see `Interpreter::to_rust`.
+fn transpile(
+    program: &Program,
+    code_lines: &HashMap<u16, Statement>,
+    variables: &HashMap<String, Value>,
+    seed: u64,
+    max_call_depth: usize,
+) -> Result<String, InterpreterError> {
+    let line_count = program.instructions.iter().
+        position(|instruction| matches!(instruction, Instruction::Halt)).
+        expect("compiled programs end with Halt");
+    let transpiler = Transpiler { program, line_count };
+
+    let names = |names: &[String]| names.iter().map(|name| format!("{name:?}")).collect::<Vec<_>>().join(", ");
+    let values = |values: Vec<String>| values.iter().map(|value| format!("        {value},\n")).collect::<String>();
+
+    let mut source = "// Transpiled from BASIC. INPUT reads from stdin and PRINT writes to stdout. A runtime error\n\
+        // or STOP is printed to stderr and exits with status 1.\n\
+        #![allow(dead_code, unreachable_code, unused_mut, unused_variables)]\n\n".to_owned();
+    source += RUST_RUNTIME;
+    source += RUST_MACHINE;
+    source += &format!("\nconst SLOT_NAMES: &[&str] = &[{}];\n", names(&program.slot_names));
+    source += &format!("const ARRAY_NAMES: &[&str] = &[{}];\n", names(&program.array_names));
+    source += &format!("const SEED: u64 = {seed};\n");
+    source += &format!("const MAX_CALL_DEPTH: usize = {max_call_depth};\n");
+
+    // Values outside the lines can't be blamed on one, but they all come from finite numbers anyway.
+    let cannot_transpile = |reason| InterpreterError::CannotTranspile { line_number: None, reason };
+    let initial_slots = program.slot_names.iter().
+        map(|name| match variables.get(name) {
+            Some(value) => Ok(format!("Some({})", rust_value(value)?)),
+            None => Ok("None".to_owned()),
+        }).
+        collect::<Result<_, String>>().
+        map_err(cannot_transpile)?;
+    source += &format!("\nfn initial_slots() -> Vec<Option<Value>> {{\n    vec![\n{}    ]\n}}\n", values(initial_slots));
+    let data = program.data.iter().map(rust_value).collect::<Result<_, String>>().map_err(cannot_transpile)?;
+    source += &format!("\nfn data() -> Vec<Value> {{\n    vec![\n{}    ]\n}}\n", values(data));
+
+    for (index, instruction) in program.instructions[..line_count].iter().enumerate() {
+        let line_number = program.line_numbers[index];
+        let body = transpiler.statement(instruction, index).
+            map_err(|reason| InterpreterError::CannotTranspile { line_number: Some(line_number), reason })?;
+
+        source += &format!("\n// {line_number} {}\n", code_lines[&line_number]);
+        source += &format!(
+            "fn line_{line_number}<R: BufRead, W: Write>(m: &mut Machine<R, W>) -> Result<Option<u16>, Fault> {{\n"
+        );
+        let returns = body.last().is_some_and(|line| line.starts_with("return "));
+        for line in body {
+            source += &format!("    {line}\n");
+        }
+        if !returns {
+            source += &format!("    Ok({})\n", transpiler.state(index + 1));
+        }
+        source += "}\n";
+    }
+
+    source += "\nfn run<R: BufRead, W: Write>(m: &mut Machine<R, W>) -> Result<(), (u16, Fault)> {\n";
+    source += &format!("    let mut line: Option<u16> = {};\n", transpiler.state(0));
+    source += "    while let Some(line_number) = line {\n";
+    source += "        let result: Result<Option<u16>, Fault> = match line_number {\n";
+    for line_number in &program.line_numbers[..line_count] {
+        source += &format!("            {line_number} => line_{line_number}(m),\n");
+    }
+    source += "            _ => unreachable!(\"only existing lines are jumped to\"),\n";
+    source += "        };\n";
+    source += "        line = result.map_err(|fault| (line_number, fault))?;\n";
+    source += "    }\n";
+    source += "    Ok(())\n";
+    source += "}\n";
+
+    Ok(source)
+}
+
+/// Builds the control flow graph of a compiled program. GOSUB is modelled as a jump into the
+/// subroutine and every RETURN as a jump back to every line following a GOSUB, which is
+/// imprecise but never misses a path.
+fn control_flow(program: &Program) -> Vec<Vec<usize>> {
+    fn unconditional(instruction: &Instruction) -> &Instruction {
+        match instruction {
+            Instruction::If { then, .. } => unconditional(then),
+            instruction => instruction,
+        }
+    }
+
+    let return_sites = program.instructions.iter().
+        enumerate().
+        filter(|(_, instruction)| matches!(unconditional(instruction), Instruction::Gosub(_))).
+        map(|(index, _)| index + 1).
+        collect::<Vec<_>>();
+
+    let mut loop_bodies = HashMap::new();
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        if let Instruction::For { exit: Some(exit), .. } = unconditional(instruction) {
+            loop_bodies.insert(exit - 1, index + 1);
+        }
+    }
+
+    let successors = |index: usize, instruction: &Instruction| -> Vec<usize> {
+        let mut successors = vec![index + 1];
+
+        match unconditional(instruction) {
+            Instruction::Jump(target) | Instruction::Gosub(target) => successors = vec![*target],
+            Instruction::Return => successors = return_sites.clone(),
+            Instruction::For { exit, .. } => successors.extend(exit),
+            Instruction::Next(_) => successors.extend(loop_bodies.get(&index)),
+            Instruction::Fail(_) | Instruction::Halt => successors.clear(),
+            _ => {},
+        }
+
+        if matches!(instruction, Instruction::If { .. }) && !successors.contains(&(index + 1)) {
+            successors.push(index + 1);
+        }
+        successors
+    };
+
+    program.instructions.iter().
+        enumerate().
+        map(|(index, instruction)| successors(index, instruction)).
+        collect()
+}
+
+fn loads(code: &[Op]) -> impl Iterator<Item = usize> + '_ {
+    code.iter().filter_map(|op| if let Op::Load(slot) = op { Some(*slot) } else { None })
+}
+
+/// The slots an instruction assigns.
+enum Assignment {
+    Nothing,
+    Slot(usize),
+    /// Extension statements get all variables to change, so any slot may be assigned afterwards.
+    AnySlot,
+}
+
+/// Assigning to an array element reads the slots in its indices but doesn't assign a slot.
+fn place_accesses(place: &Place, mut reads: Vec<usize>) -> (Vec<usize>, Assignment) {
+    match place {
+        Place::Slot(slot) => (reads, Assignment::Slot(*slot)),
+        Place::Element { indices, .. } => {
+            reads.extend(indices.iter().flat_map(|index| loads(index)));
+            (reads, Assignment::Nothing)
+        },
+    }
+}
+
+/// The slots an instruction reads, and the slots it assigns, in execution order.
+fn slot_accesses(instruction: &Instruction) -> (Vec<usize>, Assignment) {
+    match instruction {
+        Instruction::Print(code) => (loads(code).collect(), Assignment::Nothing),
+        Instruction::Read(place) | Instruction::Input { place, .. } => place_accesses(place, vec![]),
+        Instruction::Let(place, code) => place_accesses(place, loads(code).collect()),
+        Instruction::Dim(arrays) => {
+            let reads = arrays.iter().flat_map(|(_, bounds)| bounds.iter().flat_map(|bound| loads(bound)));
+            (reads.collect(), Assignment::Nothing)
+        },
+        Instruction::For { slot, start, end, step, .. } => {
+            let mut reads = loads(start).chain(loads(end)).collect::<Vec<_>>();
+            if let Some(step) = step {
+                reads.extend(loads(step));
+            }
+            (reads, Assignment::Slot(*slot))
+        },
+        Instruction::Next(slot) => (slot.iter().copied().collect(), Assignment::Nothing),
+        Instruction::If { condition, then } => {
+            // Whatever the THEN branch assigns is only assigned sometimes, so it doesn't count.
+            let (then_reads, _) = slot_accesses(then);
+            (loads(condition).chain(then_reads).collect(), Assignment::Nothing)
+        },
+        Instruction::Extension { .. } => (vec![], Assignment::AnySlot),
+        _ => (vec![], Assignment::Nothing),
+    }
+}
+
+fn check_program(
+    code_lines: &HashMap<u16, Statement>,
+    functions: &HashMap<String, Function>,
+    variables: &HashMap<String, Value>,
+) -> Vec<Diagnostic> {
+    let program = compile(code_lines, functions);
+    let line_count = code_lines.len();
+    let successors = control_flow(&program);
+
+    let mut predecessors = vec![Vec::new(); successors.len()];
+    for (index, targets) in successors.iter().enumerate() {
+        for target in targets {
+            predecessors[*target].push(index);
+        }
+    }
+
+    // Forward reachability from the first line.
+    let mut reachable = vec![false; successors.len()];
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        if !std::mem::replace(&mut reachable[index], true) {
+            stack.extend(&successors[index]);
+        }
+    }
+
+    // Backward reachability from every instruction that ends the program.
+    let mut terminates = vec![false; successors.len()];
+    let mut stack = (0..successors.len()).filter(|i| successors[*i].is_empty()).collect::<Vec<_>>();
+    while let Some(index) = stack.pop() {
+        if !std::mem::replace(&mut terminates[index], true) {
+            stack.extend(&predecessors[index]);
+        }
+    }
+
+    // Definite assignment: a slot is assigned before an instruction if it's assigned on every
+    // path leading to it.
+    let slot_count = program.slot_names.len();
+    let initial = program.slot_names.iter().map(|name| variables.contains_key(name)).collect::<Vec<_>>();
+    let mut assigned_before = vec![vec![true; slot_count]; successors.len()];
+    assigned_before[0] = initial.clone();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for index in (0..successors.len()).filter(|i| reachable[*i]) {
+            let mut assigned = if index == 0 { initial.clone() } else { vec![true; slot_count] };
+            for predecessor in predecessors[index].iter().filter(|p| reachable[**p]) {
+                let mut after = assigned_before[*predecessor].clone();
+                match slot_accesses(&program.instructions[*predecessor]) {
+                    (_, Assignment::Slot(slot)) => after[slot] = true,
+                    (_, Assignment::AnySlot) => after.fill(true),
+                    (_, Assignment::Nothing) => {},
+                }
+                for (value, after) in assigned.iter_mut().zip(after) {
+                    *value &= after;
+                }
+            }
+
+            if assigned != assigned_before[index] {
+                assigned_before[index] = assigned;
+                changed = true;
+            }
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+
+    for index in 0..line_count {
+        let line_number = program.line_numbers[index];
+
+        let target = code_lines[&line_number].jump_target();
+        if let Some(target) = target.filter(|t| !code_lines.contains_key(t)) {
+            diagnostics.push(Diagnostic::MissingJumpTarget { line_number, target });
+        }
+
+        if !reachable[index] {
+            diagnostics.push(Diagnostic::UnreachableLine { line_number });
+            continue;
+        }
+
+        let (reads, _) = slot_accesses(&program.instructions[index]);
+        let mut reported = HashSet::new();
+        for slot in reads.into_iter().filter(|slot| !assigned_before[index][*slot]) {
+            if reported.insert(slot) {
+                let name = program.slot_names[slot].clone();
+                diagnostics.push(Diagnostic::UninitializedVariable { line_number, name });
+            }
+        }
+
+        if !terminates[index] {
+            diagnostics.push(Diagnostic::NeverTerminates { line_number });
+        }
+    }
+
+    diagnostics
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Name(String),
+    Symbol(&'static str),
+    /// Anything that can't be tokenized, e.g. an unterminated string. Parsing fails if it's
+    /// reached, but the text after it is still available for IF ... THEN.
+    Invalid,
+}
+
+const SYMBOLS: [&str; 14] = ["<=", ">=", "<>", "+", "-", "*", "/", "(", ")", ",", ";", "=", "<", ">"];
+
+/// The length of the number literal at the start of the input: digits, optionally followed by a
+/// fractional part and an exponent, e.g. `12`, `1.5` or `2.5e-3`.
+fn number_length(input: &str) -> usize {
+    let bytes = input.as_bytes();
+    let digits_from = |start: usize| {
+        bytes[start.min(bytes.len())..].iter().take_while(|b| b.is_ascii_digit()).count()
+    };
+
+    let mut length = digits_from(0);
+
+    if bytes.get(length) == Some(&b'.') && digits_from(length + 1) > 0 {
+        length += 1 + digits_from(length + 1);
+    }
+
+    if matches!(bytes.get(length), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(length + 1), Some(b'+' | b'-')));
+        let exponent_digits = digits_from(length + 1 + sign);
+        if exponent_digits > 0 {
+            length += 1 + sign + exponent_digits;
+        }
+    }
+
+    length
+}
+
+/// Splits the input into tokens, each paired with its byte range.
+fn tokenize(input: &str) -> Vec<(Range<usize>, Token)> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(offset, c)) = chars.peek() {
+        let token = if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if c.is_ascii_digit() {
+            let length = number_length(&input[offset..]);
+            for _ in 0..length {
+                chars.next();
+            }
+
+            let number = &input[offset..offset + length];
+            match parse_number(number) {
+                Some(Value::Int(number)) => Token::Int(number),
+                Some(Value::Float(number)) if !number.is_finite() => Token::Invalid,
+                Some(Value::Float(number)) => Token::Float(number),
+                _ => Token::Invalid,
+            }
+        } else if c == '"' {
+            chars.next();
+            let mut string = Some(String::new());
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, d)) => string.iter_mut().for_each(|s| s.push(d)),
+                    None => {
+                        string = None;
+                        break;
+                    },
+                }
+            }
+            string.map_or(Token::Invalid, Token::Str)
+        } else if c.is_uppercase() {
+            let mut name = String::new();
+            while let Some((_, d)) = chars.next_if(|(_, d)| d.is_alphanumeric()) {
+                name.push(d);
+            }
+            if chars.next_if(|(_, d)| *d == '$').is_some() {
+                name.push('$');
+            }
+            Token::Name(name)
+        } else {
+            match SYMBOLS.into_iter().find(|s| input[offset..].starts_with(s)) {
+                Some(symbol) => {
+                    for _ in 0..symbol.len() {
+                        chars.next();
+                    }
+                    Token::Symbol(symbol)
+                },
+                None => {
+                    chars.next();
+                    Token::Invalid
+                },
+            }
+        };
+
+        let end = chars.peek().map_or(input.len(), |(end, _)| *end);
+        tokens.push((offset..end, token));
+    }
+
+    tokens
+}
+
+/// Recursive descent parser for arithmetic expressions and IF conditions. Expression precedence
+/// from lowest to highest: `+ -`, then `* /`, then unary minus, then numbers, variables and
+/// parentheses. Conditions are comparisons combined with `OR`, then `AND`, then `NOT`.
+struct ExprParser<'a> {
+    input: &'a str,
+    functions: &'a HashMap<String, Function>,
+    tokens: Vec<Token>,
+    spans: Vec<Range<usize>>,
+    position: usize,
+    /// The furthest token position where parsing failed, with what was expected there.
+    failure: Option<(usize, Vec<String>)>,
+}
+
+impl<'a> ExprParser<'a> {
+    fn new(input: &'a str, functions: &'a HashMap<String, Function>) -> Self {
+        let (spans, tokens) = tokenize(input).into_iter().unzip();
+        Self { input, functions, tokens, spans, position: 0, failure: None }
+    }
+
+    /// Records that `expected` would have been valid at token `position`. Only the failure
+    /// furthest into the line is kept, since that's where the mistake is after backtracking.
+    fn expected(&mut self, position: usize, expected: &str) {
+        match &mut self.failure {
+            Some((furthest, alternatives)) if *furthest == position => {
+                if !alternatives.iter().any(|a| a == expected) {
+                    alternatives.push(expected.to_owned());
+                }
+            },
+            Some((furthest, _)) if *furthest > position => {},
+            _ => self.failure = Some((position, vec![expected.to_owned()])),
+        }
+    }
+
+    /// The error for the furthest failure, e.g. "expected ")" or "+"", spanning the token
+    /// where it happened.
+    fn error(&self) -> LineError {
+        let (position, alternatives) = self.failure.clone().unwrap_or((self.position, Vec::new()));
+        let span = self.spans.get(position).cloned().unwrap_or(self.input.len()..self.input.len());
+
+        let message = match alternatives.split_last() {
+            None => "syntax error".to_owned(),
+            Some((last, [])) => format!("expected {last}"),
+            Some((last, rest)) => format!("expected {} or {last}", rest.join(", ")),
+        };
+        LineError::new(span, message)
+    }
+
+    /// The byte range from the token at `start` to the last consumed token.
+    fn span_from(&self, start: usize) -> Range<usize> {
+        let end = self.position.min(self.tokens.len());
+        match (self.spans.get(start), end.checked_sub(1).and_then(|last| self.spans.get(last))) {
+            (Some(first), Some(last)) if start < end => first.start..last.end,
+            (Some(first), _) => first.clone(),
+            _ => self.input.len()..self.input.len(),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// The raw input starting at the next token.
+    fn remaining_input(&self) -> &'a str {
+        let offset = self.spans.get(self.position).map_or(self.input.len(), |span| span.start);
+        &self.input[offset..]
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(name)) if name == keyword)
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Option<()> {
+        match self.next() {
+            Some(Token::Symbol(s)) if s == symbol => Some(()),
+            _ => {
+                self.expected(self.position - 1, &format!("\"{symbol}\""));
+                None
+            },
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Option<()> {
+        match self.next() {
+            Some(Token::Name(name)) if name == keyword => Some(()),
+            _ => {
+                self.expected(self.position - 1, keyword);
+                None
+            },
+        }
+    }
+
+    fn expect_end(&mut self) -> Option<()> {
+        if self.peek().is_some() {
+            self.expected(self.position, "end of line");
+            return None;
+        }
+        Some(())
+    }
+
+    fn parse_name(&mut self) -> Option<String> {
+        match self.next() {
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => Some(name),
+            _ => {
+                self.expected(self.position - 1, "variable name");
+                None
+            },
+        }
+    }
+
+    /// Parses a variable, or an array element if the name is followed by indices in parentheses.
+    fn parse_target(&mut self) -> Option<Target> {
+        let name = self.parse_name()?;
+
+        if self.peek() != Some(&Token::Symbol("(")) {
+            return Some(Target::Variable(name));
+        }
+
+        self.next();
+        let mut indices = Vec::new();
+        loop {
+            let start = self.position;
+            let index = self.parse_expr()?;
+            if index.value_type() != Some(ValueType::Number) {
+                self.expected(start, "number expression");
+                return None;
+            }
+            indices.push(index);
+
+            if self.peek() != Some(&Token::Symbol(",")) {
+                break;
+            }
+            self.next();
+        }
+        self.expect_symbol(")")?;
+
+        Some(Target::Element { name, indices })
+    }
+
+    fn parse_to_end(&mut self) -> Option<Expr> {
+        let expr = self.parse_expr()?;
+        self.expect_end()?;
+        Some(expr)
+    }
+
+    fn parse_condition(&mut self) -> Option<Condition> {
+        let mut left = self.parse_and_condition()?;
+
+        while self.peek_keyword("OR") {
+            self.next();
+            let right = self.parse_and_condition()?;
+            left = Condition::Or(Box::new(left), Box::new(right));
+        }
+
+        Some(left)
+    }
+
+    fn parse_and_condition(&mut self) -> Option<Condition> {
+        let mut left = self.parse_not_condition()?;
+
+        while self.peek_keyword("AND") {
+            self.next();
+            let right = self.parse_not_condition()?;
+            left = Condition::And(Box::new(left), Box::new(right));
+        }
+
+        Some(left)
+    }
+
+    fn parse_not_condition(&mut self) -> Option<Condition> {
+        if self.peek_keyword("NOT") {
+            self.next();
+            return Some(Condition::Not(Box::new(self.parse_not_condition()?)));
+        }
+
+        // A parenthesis may open either a nested condition or the left side of a comparison,
+        // so try the former and backtrack if it doesn't pan out.
+        if self.peek() == Some(&Token::Symbol("(")) {
+            let start = self.position;
+            self.next();
+
+            if let Some(condition) = self.parse_condition() {
+                if self.expect_symbol(")").is_some() {
+                    return Some(condition);
+                }
+            }
+            self.position = start;
+        }
+
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Option<Condition> {
+        let left_start = self.position;
+        let left = self.parse_expr()?;
+        let op = match self.next() {
+            Some(Token::Symbol(">"))  => CompareOp::Greater,
+            Some(Token::Symbol(">=")) => CompareOp::GreaterOrEqual,
+            Some(Token::Symbol("<"))  => CompareOp::Less,
+            Some(Token::Symbol("<=")) => CompareOp::LessOrEqual,
+            Some(Token::Symbol("="))  => CompareOp::Equal,
+            Some(Token::Symbol("<>")) => CompareOp::NotEqual,
+            _ => {
+                self.expected(self.position - 1, "comparison");
+                return None;
+            },
+        };
+        let right_start = self.position;
+        let right = self.parse_expr()?;
+
+        match (left.value_type(), right.value_type()) {
+            (None, _) => {
+                self.expected(left_start, "expression of a single type");
+                None
+            },
+            (Some(left_type), right_type) if right_type != Some(left_type) => {
+                let expected = match left_type {
+                    ValueType::Number => "number expression",
+                    ValueType::Str => "string expression",
+                };
+                self.expected(right_start, expected);
+                None
+            },
+            _ => Some(Condition::Compare { left, op, right }),
+        }
+    }
+
+    fn parse_expr(&mut self) -> Option<Expr> {
+        let mut left = self.parse_term()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinaryOp::Add,
+                Some(Token::Symbol("-")) => BinaryOp::Sub,
+                _ => return Some(left),
+            };
+            self.next();
+
+            let right = self.parse_term()?;
+            left = Expr::Binary { op, left: Box::new(left), right: Box::new(right) };
+        }
+    }
+
+    fn parse_term(&mut self) -> Option<Expr> {
+        let mut left = self.parse_factor()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinaryOp::Mul,
+                Some(Token::Symbol("/")) => BinaryOp::Div,
+                _ => return Some(left),
+            };
+            self.next();
+
+            let right = self.parse_factor()?;
+            left = Expr::Binary { op, left: Box::new(left), right: Box::new(right) };
+        }
+    }
+
+    fn parse_factor(&mut self) -> Option<Expr> {
+        let Some(token) = self.next() else {
+            self.expected(self.position - 1, "expression");
+            return None;
+        };
+
+        match token {
+            Token::Int(number) => Some(Expr::Int(number)),
+            Token::Float(number) => Some(Expr::Float(number)),
+            Token::Str(string) => Some(Expr::Str(string)),
+            Token::Name(name) if KEYWORDS.contains(&name.as_str()) => {
+                self.expected(self.position - 1, "expression");
+                None
+            },
+            Token::Name(name)
+                if self.functions.contains_key(&name) && self.peek() == Some(&Token::Symbol("(")) => {
+                self.next();
+
+                let mut arguments = Vec::new();
+                if self.peek() != Some(&Token::Symbol(")")) {
+                    arguments.push(self.parse_expr()?);
+                    while self.peek() == Some(&Token::Symbol(",")) {
+                        self.next();
+                        arguments.push(self.parse_expr()?);
+                    }
+                }
+                self.expect_symbol(")")?;
+
+                Some(Expr::Call { name, arguments })
+            },
+            Token::Name(_) => {
+                self.position -= 1;
+                match self.parse_target()? {
+                    Target::Variable(name) => Some(Expr::Variable(name)),
+                    Target::Element { name, indices } => Some(Expr::Element { name, indices }),
+                }
+            },
+            Token::Symbol("-") => Some(Expr::Negate(Box::new(self.parse_factor()?))),
+            Token::Symbol("(") => {
+                let expr = self.parse_expr()?;
+                self.expect_symbol(")")?;
+                Some(expr)
+            },
+            _ => {
+                self.expected(self.position - 1, "expression");
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    /// Runs a program in the interpreter, returning its output and error message.
+    fn interpret(program: &str, input: &str) -> (String, Option<String>) {
+        let mut output = Vec::new();
+        let error = {
+            let mut interpreter = Interpreter::new(input.as_bytes(), &mut output);
+            interpreter.load(program).expect("test programs are valid");
+            match interpreter.run() {
+                Ok(RunOutcome::Finished) => None,
+                // Transpiled programs report STOP like an error, since they can't continue.
+                Ok(RunOutcome::Stopped { line_number }) => Some(format!("Stopped in line {line_number}")),
+                Err(e) => Some(e.to_string()),
+            }
+        };
+        (String::from_utf8(output).unwrap(), error)
+    }
+
+    /// Runs a program in the interpreter, returning its output and the result of `run`.
+    fn run_program(program: &str, input: &str) -> (String, Result<RunOutcome, InterpreterError>) {
+        let mut output = Vec::new();
+        let result = {
+            let mut interpreter = Interpreter::new(input.as_bytes(), &mut output);
+            interpreter.load(program).expect("test programs are valid");
+            interpreter.run()
+        };
+        (String::from_utf8(output).unwrap(), result)
+    }
+
+    /// Asserts that a program prints `expected_output` and then fails with a runtime error.
+    fn assert_runtime_error(program: &str, expected_output: &str, line_number: u16, message: &str) {
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, expected_output);
+        match result {
+            Err(InterpreterError::RuntimeError { line_number: actual_line_number, message: actual_message }) => {
+                assert_eq!((actual_line_number, actual_message.as_str()), (line_number, message));
+            },
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn expressions_follow_precedence() {
+        let program = "
+            10 LET A = 4
+            20 LET B = 3
+            30 LET C = 6
+            40 LET X = (A + 3) * B - C / 2
+            50 PRINT X
+            60 PRINT 2 + 3 * 4
+            70 PRINT (2 + 3) * 4
+            80 PRINT 10 - 4 - 3
+            90 PRINT 24 / 4 / 2
+            100 IF X > 2 * 8 + 1 GOTO 120
+            110 PRINT 0
+            120 PRINT 1
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "18\n14\n20\n3\n3\n1\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn unary_minus_binds_tighter_than_multiplication() {
+        let program = "
+            10 LET A = 4
+            20 PRINT -A + 1
+            30 PRINT -(A - 10) * 2
+            40 PRINT 2 * -3
+            50 PRINT - -A
+            60 IF -A < -3 THEN PRINT \"NEGATIVE\"
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "-3\n12\n-6\n4\nNEGATIVE\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn arithmetic_errors_name_the_line() {
+        assert_runtime_error("10 LET A = 0\n20 PRINT 1\n30 LET B = 5 / A", "1\n", 30, "Division by zero");
+        assert_runtime_error("10 PRINT 9223372036854775807 * 2", "", 10, "Arithmetic overflow");
+        assert_runtime_error("10 PRINT B", "", 10, "Unknown variable: B");
+    }
+
+    #[test]
+    fn return_without_gosub_fails() {
+        assert_runtime_error("10 GOSUB 40\n20 PRINT 2\n30 RETURN\n40 PRINT 1\n50 RETURN", "1\n2\n", 30, "RETURN without GOSUB");
+    }
+
+    #[test]
+    fn call_depth_is_limited() {
+        let mut output = Vec::new();
+        let result = {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.load("10 LET A = 0\n20 LET A = A + 1\n30 PRINT A\n40 GOSUB 20").unwrap();
+            interpreter.set_max_call_depth(3);
+            interpreter.run()
+        };
+
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n2\n3\n4\n");
+        assert!(matches!(
+            result,
+            Err(InterpreterError::RuntimeError { line_number: 40, ref message })
+                if message == "Stack overflow: GOSUB nested deeper than 3"
+        ));
+        assert_runtime_error("10 GOSUB 10", "", 10, "Stack overflow: GOSUB nested deeper than 256");
+    }
+
+    #[test]
+    fn nested_loops_count_down() {
+        let program = "
+            10 FOR I = 3 TO 1 STEP -1
+            20 FOR J = 1 TO I
+            30 PRINT I * 10 + J
+            40 NEXT J
+            50 NEXT I
+            60 PRINT I
+            70 FOR K = 1 TO 0
+            80 PRINT 0
+            90 NEXT K
+            100 PRINT K
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "31\n32\n33\n21\n22\n11\n1\n1\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn next_without_for_fails() {
+        assert_runtime_error("10 FOR I = 1 TO 2\n20 NEXT I\n30 NEXT I", "", 30, "NEXT without FOR");
+    }
+
+    #[test]
+    fn next_must_match_innermost_loop() {
+        let program = "
+            10 FOR I = 1 TO 2
+            20 FOR J = 1 TO 2
+            30 PRINT I * 10 + J
+            40 NEXT I
+            50 NEXT J
+        ";
+        assert_runtime_error(program, "11\n", 40, "NEXT I does not match FOR J");
+    }
+
+    #[test]
+    fn goto_and_if_jump_to_lines() {
+        let program = "
+            10 LET A = 1
+            20 GOTO 50
+            30 PRINT 0
+            40 LET A = 100
+            50 PRINT A
+            60 LET A = A + 1
+            70 IF A < 4 GOTO 50
+            80 IF A = 4 GOTO 100
+            90 PRINT 0
+            100 PRINT 5
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "1\n2\n3\n5\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn gosub_returns_after_the_call() {
+        let program = "
+            10 GOSUB 100
+            20 GOSUB 200
+            30 PRINT 3
+            40 GOTO 300
+            100 PRINT 1
+            110 GOSUB 200
+            120 RETURN
+            200 PRINT 2
+            210 RETURN
+            300 PRINT 4
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "1\n2\n2\n3\n4\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn jumps_to_missing_lines_fail_when_taken() {
+        let program = "
+            10 PRINT 1
+            20 IF 1 = 2 GOTO 99
+            30 GOSUB 40
+            40 PRINT 2
+            50 GOTO 99
+        ";
+        assert_runtime_error(program, "1\n2\n", 50, "Invalid line number for GOTO: 99");
+        assert_runtime_error("10 GOSUB 5", "", 10, "Invalid line number for GOSUB: 5");
+    }
+
+    #[test]
+    fn debugger_pauses_at_breakpoints() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.load("10 LET A = 1\n20 LET B = A * 2\n30 PRINT A + B\n40 PRINT 7").unwrap();
+            interpreter.set_breakpoint(20);
+
+            assert_eq!(interpreter.resume().unwrap(), DebugStatus::Paused { line_number: 20 });
+            assert_eq!(interpreter.variables().get("A"), Some(&Value::Int(1)));
+            assert_eq!(interpreter.current_line(), Some(20));
+
+            interpreter.set_variable("A", Value::Int(5)).unwrap();
+            assert_eq!(interpreter.step().unwrap(), DebugStatus::Paused { line_number: 30 });
+            assert_eq!(interpreter.variables().get("B"), Some(&Value::Int(10)));
+            assert_eq!(interpreter.step().unwrap(), DebugStatus::Paused { line_number: 40 });
+            assert_eq!(interpreter.resume().unwrap(), DebugStatus::Finished);
+            assert_eq!(interpreter.current_line(), None);
+
+            interpreter.clear_breakpoint(20);
+            assert_eq!(interpreter.resume().unwrap(), DebugStatus::Finished);
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "15\n7\n3\n7\n");
+    }
+
+    #[test]
+    fn endless_loops_run_out_of_steps() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 GOTO 10").unwrap();
+        interpreter.set_limits(Limits { max_steps: Some(1000), max_duration: None, max_inputs: None });
+
+        assert!(matches!(
+            interpreter.run(),
+            Err(InterpreterError::BudgetExceeded { budget: Budget::Steps, line_number: 10, steps: 1000 })
+        ));
+
+        interpreter.load("10 PRINT 1\n20 GOTO 20").unwrap();
+        interpreter.set_limits(Limits { max_duration: Some(Duration::from_millis(10)), ..Limits::default() });
+        assert!(matches!(
+            interpreter.run(),
+            Err(InterpreterError::BudgetExceeded { budget: Budget::Time, line_number: 20, .. })
+        ));
+    }
+
+    #[test]
+    fn input_reads_are_limited() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new("1\n2\n3\n".as_bytes(), &mut output);
+        interpreter.load("10 INPUT A\n20 GOTO 10").unwrap();
+        interpreter.set_limits(Limits { max_inputs: Some(2), ..Limits::default() });
+
+        assert!(matches!(
+            interpreter.run(),
+            Err(InterpreterError::BudgetExceeded { budget: Budget::Inputs, line_number: 10, steps: 4 })
+        ));
+    }
+
+    fn checked(program: &str) -> Vec<Diagnostic> {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load(program).expect("test programs are valid");
+        interpreter.check()
+    }
+
+    #[test]
+    fn check_reports_missing_jump_targets() {
+        assert_eq!(checked("10 GOTO 30\n20 GOSUB 5\n30 IF 1 = 1 GOTO 99"), [
+            Diagnostic::MissingJumpTarget { line_number: 20, target: 5 },
+            Diagnostic::UnreachableLine { line_number: 20 },
+            Diagnostic::MissingJumpTarget { line_number: 30, target: 99 },
+        ]);
+    }
+
+    #[test]
+    fn check_reports_variables_read_before_assignment() {
+        let program = "
+            10 PRINT A
+            20 IF A = 1 GOTO 40
+            30 LET B = 2
+            40 PRINT B
+            50 LET C = 1
+            60 PRINT C
+            70 READ D
+            80 PRINT D
+            90 DATA 4
+        ";
+        assert_eq!(checked(program), [
+            Diagnostic::UninitializedVariable { line_number: 10, name: "A".to_owned() },
+            Diagnostic::UninitializedVariable { line_number: 20, name: "A".to_owned() },
+            Diagnostic::UninitializedVariable { line_number: 40, name: "B".to_owned() },
+        ]);
+
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 PRINT A").unwrap();
+        interpreter.set_variable("A", Value::Int(1)).unwrap();
+        assert_eq!(interpreter.check(), []);
+    }
+
+    #[test]
+    fn check_reports_unreachable_lines() {
+        let program = "
+            10 GOSUB 40
+            20 GOTO 60
+            30 PRINT 1
+            40 RETURN
+            50 PRINT 2
+            60 PRINT 3
+        ";
+        assert_eq!(checked(program), [
+            Diagnostic::UnreachableLine { line_number: 30 },
+            Diagnostic::UnreachableLine { line_number: 50 },
+        ]);
+    }
+
+    #[test]
+    fn check_reports_lines_that_never_finish() {
+        assert_eq!(checked("10 PRINT 1\n20 GOTO 30\n30 GOTO 20\n40 PRINT 2"), [
+            Diagnostic::NeverTerminates { line_number: 10 },
+            Diagnostic::NeverTerminates { line_number: 20 },
+            Diagnostic::NeverTerminates { line_number: 30 },
+            Diagnostic::UnreachableLine { line_number: 40 },
+        ]);
+        assert_eq!(checked("10 LET I = 0\n20 LET I = I + 1\n30 IF I < 3 GOTO 20"), []);
+    }
+
+    #[test]
+    fn set_variable_checks_types() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        assert!(matches!(
+            interpreter.set_variable("N$", Value::Int(1)),
+            Err(InterpreterError::TypeMismatch { ref name }) if name == "N$"
+        ));
+        assert!(matches!(
+            interpreter.set_variable("N", Value::Str("1".to_owned())),
+            Err(InterpreterError::TypeMismatch { .. })
+        ));
+        assert!(interpreter.variables().is_empty());
+
+        interpreter.set_variable("N$", Value::Str("1".to_owned())).unwrap();
+        interpreter.set_variable("N", Value::Int(1)).unwrap();
+        assert_eq!(interpreter.variables().len(), 2);
+    }
+
+    #[test]
+    fn set_variable_rejects_non_finite_numbers() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        for number in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(
+                interpreter.set_variable("X", Value::Float(number)),
+                Err(InterpreterError::NotANumber { .. })
+            ));
+        }
+        assert!(interpreter.variables().is_empty());
+        interpreter.set_variable("X", Value::Float(1e300)).unwrap();
+    }
+
+    #[test]
+    fn strings_keep_their_spaces() {
+        let program = "
+            10 PRINT \"HELLO  WORLD\"
+            20 LET N$ = \"Ada \"
+            30 LET G$ = N$ + \"Lovelace\" + \"!\"
+            40 PRINT G$
+            50 IF N$ + \"X\" = \"Ada X\" GOTO 70
+            60 PRINT \"NOT EQUAL\"
+            70 IF \"ABC\" < \"ABD\" GOTO 90
+            80 PRINT \"NOT LESS\"
+            90 PRINT \"\"
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "HELLO  WORLD\nAda Lovelace!\n\n");
+        assert!(result.is_ok());
+        assert_runtime_error("10 LET A = 1\n20 PRINT A$", "", 20, "Unknown variable: A$");
+    }
+
+    #[test]
+    fn strings_and_numbers_dont_mix() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        for code in ["10 LET A$ = 1", "10 LET A = \"1\"", "10 PRINT \"A\" + 1", "10 IF \"A\" = 1 GOTO 10"] {
+            assert!(matches!(interpreter.add(code), Err(InterpreterError::SyntaxError { .. })), "{code}");
+        }
+    }
+
+    #[test]
+    fn conditions_support_every_comparison() {
+        let program = "
+            10 LET A = 3
+            20 IF A <> 4 THEN PRINT \"<>\"
+            30 IF A <= 3 THEN PRINT \"<=\"
+            40 IF A >= 3 THEN PRINT \">=\"
+            50 IF A >= 4 THEN PRINT \"WRONG >=\"
+            60 IF A <= 2 THEN PRINT \"WRONG <=\"
+            70 IF A <> 3 THEN PRINT \"WRONG <>\"
+            80 IF A > 2 AND A < 4 THEN PRINT \"AND\"
+            90 IF A = 1 OR A = 3 THEN PRINT \"OR\"
+            100 IF NOT A = 1 THEN PRINT \"NOT\"
+            110 IF NOT (A = 3 OR A = 4) THEN PRINT \"WRONG NOT\"
+            120 IF (A = 1 OR A = 3) AND NOT A > 5 THEN PRINT \"PARENTHESES\"
+            130 IF A = 1 OR A = 3 AND A = 4 THEN PRINT \"WRONG PRECEDENCE\"
+            140 IF \"ABC\" <> \"ABD\" AND \"B\" >= \"AB\" THEN PRINT \"STRINGS\"
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "<>\n<=\n>=\nAND\nOR\nNOT\nPARENTHESES\nSTRINGS\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn then_runs_a_statement() {
+        let program = "
+            10 LET A = 1
+            20 IF A = 1 THEN LET A = A + 1
+            30 IF A = 2 THEN GOSUB 100
+            40 IF A = 3 THEN IF A > 0 THEN PRINT A
+            50 IF A = 3 THEN 70
+            60 PRINT \"SKIPPED\"
+            70 GOTO 120
+            100 LET A = 3
+            110 RETURN
+            120 PRINT \"DONE\"
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "3\nDONE\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn invalid_conditions_are_syntax_errors() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        for code in ["10 IF A => 1 GOTO 10", "10 IF A = 1 GOTO", "10 IF (A = 1 THEN PRINT 1", "10 IF A AND B GOTO 10", "10 IF A = 1 PRINT 1"] {
+            assert!(matches!(interpreter.add(code), Err(InterpreterError::SyntaxError { .. })), "{code}");
+        }
+    }
+
+    #[test]
+    fn check_counts_assignments_after_then_as_conditional() {
+        assert_eq!(checked("10 IF 1 = 2 THEN LET B = 2\n20 PRINT B\n30 IF B = 1 THEN PRINT C"), [
+            Diagnostic::UninitializedVariable { line_number: 20, name: "B".to_owned() },
+            Diagnostic::UninitializedVariable { line_number: 30, name: "B".to_owned() },
+            Diagnostic::UninitializedVariable { line_number: 30, name: "C".to_owned() },
+        ]);
+    }
+
+    #[test]
+    fn arrays_hold_values() {
+        let program = "
+            10 DIM A(3), G(2, 2), N$(1)
+            20 FOR I = 0 TO 3
+            30 LET A(I) = I * I
+            40 NEXT I
+            50 PRINT A(3) + A(2)
+            60 LET G(1, 2) = 5
+            70 LET G(2, 1) = A(1) + G(1, 2)
+            80 PRINT G(2, 1)
+            90 PRINT G(0, 0)
+            100 READ N$(1)
+            110 PRINT N$(0) + N$(1)
+            120 DATA \"X\"
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "13\n6\n0\nX\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn array_errors_name_the_array_and_index() {
+        assert_runtime_error(
+            "10 DIM A(5)\n20 LET A(6) = 1",
+            "",
+            20,
+            "Index out of bounds: A(6), dimensioned as A(5)",
+        );
+        assert_runtime_error(
+            "10 DIM G(2, 3)\n20 PRINT G(1, 3)\n30 PRINT G(3, 1)",
+            "0\n",
+            30,
+            "Index out of bounds: G(3, 1), dimensioned as G(2, 3)",
+        );
+        assert_runtime_error("10 DIM A(2)\n20 PRINT A(-1)", "", 20, "Index out of bounds: A(-1), dimensioned as A(2)");
+        assert_runtime_error("10 DIM A(2)\n20 PRINT A(1, 1)", "", 20, "Index out of bounds: A(1, 1), dimensioned as A(2)");
+        assert_runtime_error("10 PRINT B(1)", "", 10, "Array B is not dimensioned");
+        assert_runtime_error("10 DIM A(65535, 65535)", "", 10, "Array A is too large");
+        assert_runtime_error("10 DIM A(-1)", "", 10, "Invalid bound for array A: -1");
+    }
+
+    #[test]
+    fn numbers_are_signed_and_wide() {
+        let program = "
+            10 INPUT A
+            20 INPUT B
+            30 PRINT A
+            40 PRINT B * 2
+            50 PRINT -70000 - 1
+            60 READ C
+            70 PRINT C
+            80 DATA -1
+            90 PRINT 9223372036854775807
+        ";
+        let (output, result) = run_program(program, "-1\n70000\n");
+        assert_eq!(output, "-1\n140000\n-70001\n-1\n9223372036854775807\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn floats_print_with_a_decimal_point() {
+        let program = "
+            10 PRINT 7 / 2
+            20 PRINT 7 / 2.0
+            30 PRINT 1.5 + 1.5
+            40 PRINT 0.1 + 0.2
+            50 PRINT 2.5e-3
+            60 PRINT 1e20 * 10
+            70 PRINT -0.5
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "3\n3.5\n3.0\n0.30000000000000004\n0.0025\n1e21\n-0.5\n");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        assert_runtime_error("10 LET A = 9223372036854775807\n20 LET A = A + 1", "", 20, "Arithmetic overflow");
+        assert_runtime_error("10 LET A = 0 - 9223372036854775807 - 1\n20 PRINT -A", "", 20, "Arithmetic overflow");
+        assert_runtime_error("10 PRINT 1e300 * 1e300", "", 10, "Arithmetic overflow");
+    }
+
+    #[test]
+    fn eval_value_returns_values() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.set_variable("X", Value::Float(2.5)).unwrap();
+
+        assert_eq!(interpreter.eval_value("-70000").unwrap(), Value::Int(-70000));
+        assert_eq!(interpreter.eval_value("1.25").unwrap(), Value::Float(1.25));
+        assert_eq!(interpreter.eval_value("X").unwrap(), Value::Float(2.5));
+        assert!(matches!(interpreter.eval_value("Y"), Err(InterpreterError::UnknownVariable { .. })));
+        assert!(matches!(interpreter.eval_value("x"), Err(InterpreterError::NotANumber { .. })));
+    }
+
+    #[test]
+    fn read_takes_values_from_data() {
+        let program = "
+            10 READ A
+            20 READ N$
+            30 PRINT N$ + \"=\"
+            40 PRINT A
+            50 DATA 1, \"ONE, TWO\"
+            60 READ B
+            70 PRINT B
+            80 RESTORE
+            90 READ C
+            100 PRINT C
+            110 DATA -2.5
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "ONE, TWO=\n1\n-2.5\n1\n");
+        assert!(result.is_ok());
+
+        assert_runtime_error("10 DATA 1\n20 READ A\n30 PRINT A\n40 READ B", "1\n", 40, "Out of DATA");
+        assert_runtime_error("10 DATA \"X\"\n20 READ A", "", 20, "Type mismatch");
+    }
+
+    #[test]
+    fn input_prompts_and_asks_again() {
+        let program = "
+            10 INPUT \"Age? \"; A
+            20 INPUT N$
+            30 PRINT A + 1
+            40 PRINT N$
+        ";
+        let (output, result) = run_program(program, "old\n 41 \nAda Lovelace\n");
+        assert_eq!(output, "Age? Not a number: old, try again\nAge? 42\nAda Lovelace\n");
+        assert!(result.is_ok());
+        assert_runtime_error("10 INPUT A", "", 10, "No more input");
+    }
+
+    #[test]
+    fn builtin_functions_compute() {
+        let program = "
+            10 PRINT ABS(-3) + ABS(2)
+            20 PRINT ABS(-1.5)
+            30 PRINT MOD(17, 5)
+            40 PRINT MOD(-7, 2)
+            50 PRINT MIN(4, 2.5, 9)
+            60 PRINT MAX(4, -2, 9)
+            70 PRINT SQR(16)
+        ";
+        let (output, result) = run_program(program, "");
+        assert_eq!(output, "5\n1.5\n2\n-1\n2.5\n9\n4.0\n");
+        assert!(result.is_ok());
+
+        assert_runtime_error("10 PRINT MOD(1, 0)", "", 10, "Division by zero");
+        assert_runtime_error("10 PRINT SQR(-1)", "", 10, "Invalid argument for SQR");
+        assert_runtime_error("10 PRINT RND(0)", "", 10, "Invalid argument for RND");
+        assert_runtime_error("10 PRINT ABS(1, 2)", "", 10, "Wrong number of arguments for ABS");
+        assert_runtime_error("10 PRINT MIN(\"B\", \"A\")", "", 10, "Type mismatch");
+    }
+
+    #[test]
+    fn rnd_repeats_for_the_same_seed() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.load("10 FOR I = 1 TO 20\n20 PRINT RND(1000)\n30 NEXT I").unwrap();
+
+            interpreter.set_seed(7);
+            interpreter.run().unwrap();
+            interpreter.run().unwrap();
+            interpreter.set_seed(8);
+            interpreter.run().unwrap();
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        let numbers = output.lines().map(|line| line.parse::<u32>().unwrap()).collect::<Vec<_>>();
+        assert!(numbers.iter().all(|n| (1..=1000).contains(n)));
+        assert_eq!(numbers[..20], numbers[20..40]);
+        assert_ne!(numbers[..20], numbers[40..]);
+    }
+
+    #[test]
+    fn native_functions_are_callable() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.register_function("HYPOT", |arguments| match arguments {
+                [Value::Int(a), Value::Int(b)] => Ok(Value::Int(((a * a + b * b) as f64).sqrt() as i64)),
+                _ => Err("expected two whole numbers".to_owned()),
+            }).unwrap();
+            interpreter.register_function("GREET$", |arguments| Ok(Value::Str(format!("HI {}", arguments[0])))).unwrap();
+            interpreter.load("10 PRINT HYPOT(3, 4) + 1\n20 PRINT GREET$(\"ADA\")\n30 PRINT HYPOT(1)").unwrap();
+
+            assert!(matches!(
+                interpreter.run(),
+                Err(InterpreterError::RuntimeError { line_number: 30, ref message })
+                    if message == "Error in HYPOT: expected two whole numbers"
+            ));
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "6\nHI ADA\n");
+    }
+
+    #[test]
+    fn native_functions_must_return_finite_numbers() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.register_function("INF", |_| Ok(Value::Float(f64::INFINITY))).unwrap();
+        interpreter.load("10 LET X = INF(1)").unwrap();
+
+        assert!(matches!(
+            interpreter.run(),
+            Err(InterpreterError::RuntimeError { line_number: 10, ref message })
+                if message == "Error in INF: returned inf, which isn't a finite number"
+        ));
+        assert!(!interpreter.variables().contains_key("X"));
+    }
+
+    #[test]
+    fn uncallable_function_names_are_rejected() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        for name in ["twice", "TWO WORDS", "", "2X", "A-B", "AND", "STEP"] {
+            assert!(
+                matches!(interpreter.register_function(name, |_| Ok(Value::Int(0))), Err(InterpreterError::InvalidName { .. })),
+                "{name}",
+            );
+        }
+        assert!(interpreter.register_function("X2$", |_| Ok(Value::Str(String::new()))).is_ok());
+    }
+
+    /// `MOVE NORTH` and friends, which move the position in `X` and `Y` and print it.
+    struct Move;
+
+    impl StatementExtension for Move {
+        fn parse(&self, arguments: &str) -> Option<String> {
+            ["NORTH", "SOUTH", "EAST", "WEST"].contains(&arguments.trim()).then(|| arguments.trim().to_owned())
+        }
+
+        fn execute(
+            &self,
+            arguments: &str,
+            variables: &mut HashMap<String, Value>,
+            output: &mut dyn Write,
+        ) -> Result<(), String> {
+            let (name, delta) = match arguments {
+                "NORTH" => ("Y", 1),
+                "SOUTH" => ("Y", -1),
+                "EAST" => ("X", 1),
+                _ => ("X", -1),
+            };
+
+            for coordinate in ["X", "Y"] {
+                variables.entry(coordinate.to_owned()).or_insert(Value::Int(0));
+            }
+            let Value::Int(position) = variables[name] else {
+                return Err(format!("{name} is not a whole number"));
+            };
+            variables.insert(name.to_owned(), Value::Int(position + delta));
+
+            writeln!(output, "AT {}, {}", variables["X"], variables["Y"]).map_err(|e| e.to_string())
+        }
+    }
+
+    #[test]
+    fn extension_statements_parse_run_and_list() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.register_statement("MOVE", Move).unwrap();
+            interpreter.load("10 MOVE   NORTH\n20 IF Y = 1 THEN MOVE EAST\n30 PRINT X + Y").unwrap();
+
+            assert_eq!(interpreter.list(), ["10 MOVE NORTH", "20 IF Y = 1 THEN MOVE EAST", "30 PRINT X + Y"]);
+            assert!(matches!(interpreter.add("40 MOVE UP"), Err(InterpreterError::SyntaxError { .. })));
+            assert!(matches!(interpreter.add("40 MOVE"), Err(InterpreterError::SyntaxError { .. })));
+
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.variables().get("X"), Some(&Value::Int(1)));
+
+            interpreter.set_variable("X", Value::Float(0.5)).unwrap();
+            interpreter.add("5 MOVE WEST").unwrap();
+            assert!(matches!(
+                interpreter.run(),
+                Err(InterpreterError::RuntimeError { line_number: 5, ref message }) if message == "X is not a whole number"
+            ));
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "AT 0, 1\nAT 1, 1\n2\n");
+    }
+
+    #[test]
+    fn unusable_statement_keywords_are_rejected() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        for keyword in ["MOVE NORTH", "move", "", "2D", "PRINT", "RESTORE", "THEN", "BEEP!"] {
+            assert!(matches!(interpreter.register_statement(keyword, Move), Err(InterpreterError::InvalidName { .. })), "{keyword}");
+        }
+        assert!(interpreter.register_statement("BEEP2", Move).is_ok());
+    }
+
+    #[test]
+    fn check_assumes_extensions_assign_variables() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.register_statement("MOVE", Move).unwrap();
+        interpreter.load("10 PRINT Y\n20 MOVE NORTH\n30 PRINT Y").unwrap();
+
+        assert_eq!(interpreter.check(), [Diagnostic::UninitializedVariable { line_number: 10, name: "Y".to_owned() }]);
+    }
+
+    fn traced(program: &str, variables: bool) -> String {
+        let mut output = Vec::new();
+        let mut trace = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.load(program).unwrap();
+            interpreter.set_trace(Some(Trace { writer: Box::new(&mut trace), variables }));
+            interpreter.run().unwrap();
+        }
+        String::from_utf8(trace).unwrap()
+    }
+
+    #[test]
+    fn trace_lists_executed_lines() {
+        let program = "10 LET A = 1\n20 GOTO 40\n30 PRINT 0\n40 LET S$ = \"X\"\n50 LET A = A\n60 FOR I = 1 TO 2\n70 NEXT I";
+
+        assert_eq!(traced(program, false), "[10]\n[20]\n[40]\n[50]\n[60]\n[70]\n[70]\n");
+        assert_eq!(
+            traced(program, true),
+            "[10] A = 1\n[20]\n[40] S$ = \"X\"\n[50]\n[60] I = 1\n[70] I = 2\n[70]\n",
+        );
+    }
+
+    #[test]
+    fn profile_counts_lines_and_ranks_loops() {
+        let program = "
+            10 LET S = 0
+            20 FOR I = 1 TO 3
+            30 FOR J = 1 TO 4
+            40 LET S = S + J
+            50 NEXT J
+            60 NEXT I
+            70 PRINT S
+        ";
+
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load(program).unwrap();
+        assert_eq!(interpreter.profile(), None);
+        interpreter.run().unwrap();
+
+        assert_eq!(interpreter.profile(), Some(&Profile {
+            line_counts: vec![(10, 1), (20, 1), (30, 3), (40, 12), (50, 12), (60, 3), (70, 1)],
+            hottest_loops: vec![
+                LoopProfile { first_line: 30, last_line: 60, iterations: 2, steps_in_range: 30 },
+                LoopProfile { first_line: 40, last_line: 50, iterations: 9, steps_in_range: 24 },
+            ],
+        }));
+    }
+
+    /// An input reader for programs that must never read from it.
+    struct Unreadable;
+
+    impl Read for Unreadable {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            panic!("the input reader was read");
+        }
+    }
+
+    #[test]
+    fn run_for_waits_for_fed_input() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(Unreadable, &mut output);
+            interpreter.load("10 INPUT \"A? \"; A\n20 INPUT B\n30 PRINT A + B").unwrap();
+
+            assert!(matches!(interpreter.run_for(100), RunStatus::NeedsInput));
+            assert!(matches!(interpreter.run_for(100), RunStatus::NeedsInput));
+            interpreter.feed_input("x\n1");
+            assert!(matches!(interpreter.run_for(100), RunStatus::NeedsInput));
+            interpreter.feed_input("2\n");
+            assert!(matches!(interpreter.run_for(100), RunStatus::Finished));
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "A? Not a number: x, try again\nA? 3\n");
+    }
+
+    #[test]
+    fn run_for_continues_after_the_budget() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(Unreadable, &mut output);
+            interpreter.load("10 FOR I = 1 TO 5\n20 PRINT I\n30 NEXT I").unwrap();
+
+            assert!(matches!(interpreter.run_for(4), RunStatus::BudgetExhausted));
+            assert_eq!(interpreter.variables().get("I"), Some(&Value::Int(2)));
+            assert!(matches!(interpreter.run_for(4), RunStatus::BudgetExhausted));
+            assert!(matches!(interpreter.run_for(100), RunStatus::Finished));
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n2\n3\n4\n5\n");
+    }
+
+    #[test]
+    fn restored_snapshots_continue_where_they_stopped() {
+        let program = "
+            10 DIM A(3)
+            20 FOR I = 1 TO 3
+            30 GOSUB 100
+            40 NEXT I
+            50 PRINT A(1) + A(2) + A(3)
+            60 GOTO 200
+            100 READ D$
+            110 LET A(I) = RND(1000)
+            120 FOR J = 1 TO 2
+            130 PRINT D$
+            140 PRINT I * 10 + J
+            150 NEXT J
+            160 RETURN
+            170 DATA \"ONE\", \"TWO\", \"THREE\"
+            200 PRINT I
+        ";
+
+        let mut expected = Vec::new();
+        let steps = {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut expected);
+            interpreter.load(program).unwrap();
+            interpreter.run().unwrap();
+            interpreter.profile().unwrap().line_counts.iter().map(|(_, count)| count).sum::<u64>()
+        };
+
+        for stop_after in 1..steps {
+            let mut output = Vec::new();
+            let snapshot = {
+                let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+                interpreter.load(program).unwrap();
+                assert!(matches!(interpreter.run_for(stop_after), RunStatus::BudgetExhausted));
+                interpreter.snapshot()
+            };
+
+            {
+                let mut restored = Interpreter::new(std::io::empty(), &mut output);
+                restored.restore(&snapshot).unwrap();
+                assert_eq!(restored.snapshot(), snapshot);
+                assert!(matches!(restored.run_for(steps), RunStatus::Finished));
+            }
+            assert_eq!(output, expected, "stopped after {stop_after} steps");
+        }
+    }
+
+    #[test]
+    fn snapshots_only_await_input_at_input() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 PRINT 1\n20 IF 1 = 1 THEN INPUT \"A? \"; A\n30 PRINT A").unwrap();
+        assert!(matches!(interpreter.run_for(10), RunStatus::NeedsInput));
+
+        let snapshot = interpreter.snapshot();
+        assert!(snapshot.contains("\nPC 1\n") && snapshot.contains("\nAWAITING\n"));
+        assert!(interpreter.restore(&snapshot).is_ok());
+
+        for pc in ["PC 0", "PC 2", "PC 3"] {
+            let invalid = snapshot.replace("PC 1", pc);
+            assert!(matches!(interpreter.restore(&invalid), Err(InterpreterError::InvalidSnapshot { .. })), "{pc}");
+        }
+        interpreter.feed_input("5");
+        assert!(matches!(interpreter.run_for(10), RunStatus::Finished));
+        assert_eq!(interpreter.variables().get("A"), Some(&Value::Int(5)));
+    }
+
+    #[test]
+    fn renumber_rewrites_jump_targets() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("
+            5 LET A = 1
+            7 GOSUB 40
+            8 IF A = 2 THEN 12
+            9 IF A = 1 GOTO 7
+            12 GOTO 900
+            40 LET A = A + 1
+            41 RETURN
+        ").unwrap();
+        interpreter.set_breakpoint(40);
+
+        interpreter.renumber(100, 5).unwrap();
+        assert_eq!(interpreter.list(), [
+            "100 LET A = 1",
+            "105 GOSUB 125",
+            "110 IF A = 2 GOTO 120",
+            "115 IF A = 1 GOTO 105",
+            "120 GOTO 900",
+            "125 LET A = A + 1",
+            "130 RETURN",
+        ]);
+        assert_eq!(interpreter.resume().unwrap(), DebugStatus::Paused { line_number: 125 });
+    }
+
+    #[test]
+    fn failed_renumbering_changes_nothing() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 PRINT 1\n20 GOTO 35\n30 GOTO 10").unwrap();
+        let listing = interpreter.list();
+
+        for (start, step) in [(65530, 5), (65535, 1), (0, 40000), (10, 0), (15, 20), (5, 30)] {
+            assert!(
+                matches!(interpreter.renumber(start, step), Err(InterpreterError::RenumberFailed { .. })),
+                "RENUM {start}, {step}",
+            );
+            assert_eq!(interpreter.list(), listing);
+        }
+
+        assert!(interpreter.renumber(65533, 1).is_ok());
+        assert_eq!(interpreter.list(), ["65533 PRINT 1", "65534 GOTO 35", "65535 GOTO 65533"]);
+    }
+
+    #[test]
+    fn ranges_of_lines_can_be_removed() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 PRINT 1\n20 PRINT 2\n30 PRINT 3\n40 PRINT 4\n50 PRINT 5").unwrap();
+
+        assert_eq!(interpreter.remove_range(20..=40), 3);
+        assert_eq!(interpreter.remove_range(11..20), 0);
+        assert!(interpreter.remove(50));
+        assert!(!interpreter.remove(50));
+        assert_eq!(interpreter.list(), ["10 PRINT 1"]);
+        assert_eq!(interpreter.remove_range(..), 1);
+        assert!(interpreter.list().is_empty());
+    }
+
+    #[test]
+    fn load_collects_every_syntax_error() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 PRINT 1").unwrap();
+        interpreter.set_variable("A", Value::Int(1)).unwrap();
+
+        let errors = interpreter.load("10 PRINT 1\n\n20 LET X = (1 + 2\n30 GOTO TEN\n40 PRINT \"é\" + A\n50 FROB").unwrap_err();
+        let found = errors.iter().map(|e| (e.line, e.column, e.length, e.message.as_str())).collect::<Vec<_>>();
+        assert_eq!(found, [
+            (3, 18, 1, "expected \")\""),
+            (4, 9, 3, "expected line number"),
+            (5, 10, 7, "type mismatch in expression"),
+            (6, 4, 4, "expected statement"),
+        ]);
+
+        assert_eq!(interpreter.list(), ["10 PRINT 1"]);
+        assert_eq!(interpreter.variables().get("A"), Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn parse_errors_underline_the_offending_token() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+        let errors = interpreter.load("10 PRINT \"é\" + 1\n20 GOTO TEN").unwrap_err();
+        let rendered = errors.iter().map(ParseError::render).collect::<Vec<_>>();
+        assert_eq!(rendered, [
+            "Line 1, column 10: type mismatch in expression\n    10 PRINT \"é\" + 1\n             ^^^^^^^",
+            "Line 2, column 9: expected line number\n    20 GOTO TEN\n            ^^^",
+        ]);
+
+        let error = ParseError { line: 1, column: 0, length: 1, message: "oops".to_owned(), code: "X".to_owned() };
+        assert_eq!(error.render(), "Line 1, column 0: oops\n    X\n    ^");
+    }
+
+    /// Transpiles a program, compiles it with rustc and runs it, returning its output and error
+    /// message.
+    fn transpiled(name: &str, program: &str, input: &str) -> (String, Option<String>) {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load(program).expect("test programs are valid");
+        let source = interpreter.to_rust().expect("test programs can be transpiled");
+
+        let directory = std::env::temp_dir().join(format!("basic_to_rust_{}_{name}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let source_path = directory.join("main.rs");
+        let binary = directory.join("main");
+        std::fs::write(&source_path, source).unwrap();
+
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+        let compiled = Command::new(rustc).
+            args(["--edition", "2021", "-o"]).
+            arg(&binary).
+            arg(&source_path).
+            output().
+            unwrap();
+        assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+
+        let mut child = Command::new(&binary).
+            stdin(Stdio::piped()).
+            stdout(Stdio::piped()).
+            stderr(Stdio::piped()).
+            spawn().
+            unwrap();
+        // The program may exit without reading everything.
+        let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+        let result = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&directory).ok();
+
+        let error = (!result.status.success()).
+            then(|| String::from_utf8(result.stderr).unwrap().trim_end().to_owned());
+        (String::from_utf8(result.stdout).unwrap(), error)
+    }
+
+    fn assert_same_behavior(name: &str, program: &str, input: &str) {
+        let expected = interpret(program, input);
+        assert_eq!(transpiled(name, program, input), expected, "program {name}");
+    }
+
+    #[test]
+    fn transpiled_arithmetic_matches() {
+        assert_same_behavior("arithmetic", "
+            10 LET A = 7
+            20 LET B = 2
+            30 PRINT A / B
+            40 PRINT A * B - -3
+            50 PRINT A / 2.0
+            60 PRINT 1.5 + 1.5
+            70 LET S$ = \"AB\" + \"CD\"
+            80 PRINT S$
+            90 PRINT \"DONE\"
+        ", "");
+    }
+
+    #[test]
+    fn transpiled_control_flow_matches() {
+        assert_same_behavior("control_flow", "
+            10 FOR I = 1 TO 3
+            20 FOR J = I TO 1 STEP -1
+            30 PRINT I * 10 + J
+            40 NEXT J
+            50 NEXT I
+            60 FOR X = 0 TO 1 STEP 0.25
+            70 PRINT X
+            80 NEXT
+            90 FOR K = 5 TO 1
+            100 PRINT \"NEVER\"
+            110 NEXT K
+            120 GOSUB 200
+            130 IF I > 3 AND NOT J = 0 THEN PRINT I
+            140 IF I < 0 OR K = 5 GOTO 160
+            150 PRINT \"SKIPPED\"
+            160 PRINT K
+            170 GOTO 300
+            200 PRINT \"SUBROUTINE\"
+            210 RETURN
+            300 PRINT \"END\"
+        ", "");
+        assert_same_behavior("empty", "", "");
+    }
+
+    #[test]
+    fn transpiled_arrays_and_data_match() {
+        assert_same_behavior("arrays_and_data", "
+            10 DIM A(2, 3), N$(2)
+            20 FOR I = 0 TO 2
+            30 READ N$(I)
+            40 LET A(I, I + 1) = I * I
+            50 NEXT I
+            60 PRINT A(2, 3) + A(1, 2)
+            70 PRINT N$(1)
+            80 RESTORE
+            90 READ F$
+            100 PRINT F$
+            110 DATA \"X\", \"Y\", \"Z\"
+            120 READ F$
+            130 READ F$
+            140 READ V
+            150 PRINT V
+            160 DATA -2.5
+            170 READ W
+        ", "");
+    }
+
+    #[test]
+    fn transpiled_input_matches() {
+        assert_same_behavior("input", "
+            10 INPUT \"Name? \"; N$
+            20 INPUT \"Age? \"; A
+            30 PRINT N$ + \"!\"
+            40 PRINT A + 1
+            50 INPUT X
+            60 PRINT X
+        ", "Ada Lovelace\nold\n36\n1e3\n");
+    }
+
+    #[test]
+    fn transpiled_builtins_match() {
+        assert_same_behavior("builtins", "
+            10 PRINT ABS(-3)
+            20 PRINT MOD(17, 5)
+            30 PRINT MIN(4, 2.5, 9)
+            40 PRINT MAX(4, 2, 9)
+            50 PRINT SQR(2)
+            60 FOR I = 1 TO 5
+            70 PRINT RND(100)
+            80 NEXT I
+        ", "");
+    }
+
+    #[test]
+    fn transpiled_runtime_errors_match() {
+        let programs = [
+            ("division_by_zero", "10 LET A = 0\n20 PRINT 1 / A"),
+            ("overflow", "10 LET A = 9223372036854775807\n20 PRINT A + 1"),
+            ("unknown_variable", "10 PRINT 1\n20 PRINT Q"),
+            ("out_of_bounds", "10 DIM A(5)\n20 LET A(6) = 1"),
+            ("not_dimensioned", "10 PRINT B(1)"),
+            ("missing_line", "10 PRINT 1\n20 GOTO 99"),
+            ("missing_gosub", "10 GOSUB 99"),
+            ("return_without_gosub", "10 RETURN"),
+            ("next_without_for", "10 NEXT"),
+            ("for_without_next", "10 FOR I = 2 TO 1"),
+            ("stack_overflow", "10 GOSUB 10"),
+            ("out_of_data", "10 DATA 1\n20 READ A\n30 READ B"),
+            ("no_more_input", "10 INPUT A"),
+            ("invalid_argument", "10 PRINT SQR(-1)"),
+        ];
+
+        for (name, program) in programs {
+            assert_same_behavior(name, program, "");
+        }
+    }
+
+    #[test]
+    fn transpiled_end_and_stop_match() {
+        assert_same_behavior("end", "
+            10 REM Prints 1 and 2
+            20 PRINT 1
+            30 ' then ends early
+            40 GOSUB 100
+            50 PRINT 3
+            60 END
+            100 PRINT 2
+            110 IF 1 = 1 THEN END
+            120 PRINT 4
+        ", "");
+        assert_same_behavior("stop", "10 PRINT 1\n20 STOP\n30 PRINT 2", "");
+    }
+
+    #[test]
+    fn transpiled_program_starts_with_variables_and_seed() {
+        let program = "10 PRINT A + 1\n20 PRINT RND(1000)";
+
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load(program).unwrap();
+        interpreter.set_variable("A", Value::Int(41)).unwrap();
+        interpreter.set_seed(7);
+        let source = interpreter.to_rust().unwrap();
+
+        assert!(source.contains("Some(Value::Int(41))"));
+        assert!(source.contains("const SEED: u64 = 7;"));
+    }
+
+    #[test]
+    fn non_finite_numbers_cannot_be_transpiled() {
+        for number in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(rust_value(&Value::Float(number)).is_err());
+        }
+        assert_eq!(rust_value(&Value::Float(-0.5)).unwrap(), "Value::Float(-0.5)");
+    }
+
+    #[test]
+    fn native_functions_cannot_be_transpiled() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.register_function("TWICE", |arguments| {
+            Ok(Value::Int(arguments[0].as_index().map_err(|e| e.to_string())? * 2))
+        }).unwrap();
+        interpreter.load("10 PRINT 1\n20 PRINT TWICE(2)").unwrap();
+
+        assert!(matches!(
+            interpreter.to_rust(),
+            Err(InterpreterError::CannotTranspile { line_number: Some(20), .. })
+        ));
+    }
+
+    /// A xorshift64 generator, so the randomized tests below are reproducible and need no crates.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            let mut x = self.0;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.0 = x;
+            x
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn chance(&mut self, percent: usize) -> bool {
+            self.below(100) < percent
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+    }
+
+    const FUZZ_NAMES: [&str; 6] = ["A", "B", "I", "X1", "S$", "T$"];
+
+    const FUZZ_FRAGMENTS: [&str; 44] = [
+        "PRINT", "LET", "IF", "THEN", "GOTO", "GOSUB", "RETURN", "FOR", "TO", "STEP", "NEXT", "DIM",
+        "READ", "DATA", "RESTORE", "INPUT", "REM", "'", "END", "STOP", "AND", "OR", "NOT", "(", ")",
+        ",", ";", "=", "<>", "<=",
+        "-", "*", "/", "\"", "$", "é", "ß", "\t", "1e", "0.", "1e999", "99999999999999999999", "A(", "",
+    ];
+
+    fn random_expr(rng: &mut XorShift, depth: usize) -> String {
+        let choice = if depth == 0 { rng.below(4) } else { rng.below(9) };
+        match choice {
+            0 => rng.pick(&["0", "1", "7", "-3", "2.5", "9223372036854775807", "1e300"]).to_owned(),
+            1 | 2 => rng.pick(&FUZZ_NAMES).to_owned(),
+            3 => rng.pick(&["\"\"", "\"HI\"", "\"a b\""]).to_owned(),
+            4 => {
+                let op = rng.pick(&["+", "-", "*", "/"]);
+                format!("{} {op} {}", random_expr(rng, depth - 1), random_expr(rng, depth - 1))
+            },
+            5 => format!("-{}", random_expr(rng, depth - 1)),
+            6 => format!("({})", random_expr(rng, depth - 1)),
+            7 => {
+                let name = rng.pick(&["ABS", "MOD", "MIN", "MAX", "RND", "SQR"]);
+                let arguments = (0..rng.below(3)).map(|_| random_expr(rng, depth - 1)).collect::<Vec<_>>();
+                format!("{name}({})", arguments.join(", "))
+            },
+            _ => format!("{}({})", rng.pick(&["A", "B", "S$"]), random_expr(rng, depth - 1)),
+        }
+    }
+
+    fn random_condition(rng: &mut XorShift) -> String {
+        let comparison = format!(
+            "{} {} {}",
+            random_expr(rng, 2),
+            rng.pick(&["=", "<>", "<", "<=", ">", ">="]),
+            random_expr(rng, 2),
+        );
+        match rng.below(4) {
+            0 => format!("NOT {comparison}"),
+            1 => format!("{comparison} {} {}", rng.pick(&["AND", "OR"]), random_condition(rng)),
+            _ => comparison,
+        }
+    }
+
+    fn random_statement(rng: &mut XorShift) -> String {
+        let target = if rng.chance(70) {
+            rng.pick(&FUZZ_NAMES).to_owned()
+        } else {
+            format!("{}({})", rng.pick(&["A", "B", "S$"]), random_expr(rng, 1))
+        };
+
+        match rng.below(16) {
+            0 => format!("PRINT {}", random_expr(rng, 3)),
+            1 => format!("LET {target} = {}", random_expr(rng, 3)),
+            2 => format!("IF {} THEN {}", random_condition(rng), random_statement(rng)),
+            3 => format!("IF {} GOTO {}", random_condition(rng), rng.pick(&["10", "20", "30", "99"])),
+            4 => format!("GOTO {}", rng.pick(&["10", "20", "30", "40", "99"])),
+            5 => format!("GOSUB {}", rng.pick(&["10", "20", "30", "40", "99"])),
+            6 => "RETURN".to_owned(),
+            7 => {
+                let var_name = rng.pick(&FUZZ_NAMES);
+                let (start, end, step) = (random_expr(rng, 1), random_expr(rng, 1), random_expr(rng, 1));
+                format!("FOR {var_name} = {start} TO {end} STEP {step}")
+            },
+            8 => format!("NEXT {}", rng.pick(&["", "I", "A", "S$"])),
+            9 => {
+                let (first, second) = (rng.pick(&["A", "S$"]), rng.pick(&["B", "A"]));
+                format!("DIM {first}({}), {second}({}, 2)", random_expr(rng, 1), random_expr(rng, 1))
+            },
+            10 => format!("READ {target}"),
+            11 => format!("DATA {}", rng.pick(&["1, 2", "\"X\", -2.5", "-", "1,", "\"A\""])),
+            12 => "RESTORE".to_owned(),
+            13 => format!("INPUT {target}"),
+            14 => format!("INPUT \"?\"; {target}"),
+            _ => (0..rng.below(8)).map(|_| rng.pick(&FUZZ_FRAGMENTS)).collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    /// Inserts or deletes characters at random places, to produce near misses of valid code.
+    fn mutate(rng: &mut XorShift, line: &str) -> String {
+        let mut chars = line.chars().collect::<Vec<_>>();
+        for _ in 0..rng.below(3) {
+            let position = rng.below(chars.len() + 1);
+            if rng.chance(50) && position < chars.len() {
+                chars.remove(position);
+            } else {
+                let fragment = rng.pick(&FUZZ_FRAGMENTS);
+                chars.splice(position..position, fragment.chars());
+            }
+        }
+        chars.into_iter().collect()
+    }
+
+    fn random_program(rng: &mut XorShift) -> Vec<String> {
+        (0..1 + rng.below(8)).
+            map(|index| {
+                let line_number = if rng.chance(90) {
+                    ((index + 1) * 10).to_string()
+                } else {
+                    rng.pick(&["0", "65535", "65536", "-1", "X", ""]).to_owned()
+                };
+                let line = format!("{line_number} {}", random_statement(rng));
+                if rng.chance(30) { mutate(rng, &line) } else { line }
+            }).
+            collect()
+    }
+
+    /// Runs `test` with a reproducible random generator for each seed, reporting the seed of the
+    /// first panic.
+    fn for_each_seed(seeds: std::ops::Range<u64>, test: impl Fn(&mut XorShift)) {
+        for seed in seeds {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                test(&mut XorShift(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1))
+            }));
+            assert!(result.is_ok(), "panicked with seed {seed}");
+        }
+    }
+
+    #[test]
+    fn random_programs_never_panic() {
+        for_each_seed(0..3000, |rng| {
+            let program = random_program(rng);
+            let input = (0..rng.below(4)).map(|_| random_expr(rng, 1) + "\n").collect::<String>();
+
+            let mut output = Vec::new();
+            let mut interpreter = Interpreter::new(input.as_bytes(), &mut output);
+            interpreter.set_limits(Limits { max_steps: Some(300), max_duration: None, max_inputs: Some(10) });
+            for line in &program {
+                let _ = interpreter.add(line);
+            }
+
+            let _ = interpreter.run();
+            let _ = interpreter.check();
+            let _ = interpreter.to_rust();
+            let _ = interpreter.eval_value(&random_expr(rng, 0));
+
+            let snapshot = interpreter.snapshot();
+            let _ = interpreter.restore(&snapshot);
+            interpreter.feed_input(&random_expr(rng, 0));
+            let _ = interpreter.run_for(rng.below(50) as u64);
+            let _ = interpreter.renumber(rng.below(100) as u16, rng.below(20) as u16);
+            let _ = interpreter.list();
+        });
+    }
+
+    #[test]
+    fn random_text_never_panics() {
+        let alphabet = ['1', '0', ' ', 'A', 'Z', '$', '"', '(', ')', '-', '.', 'e', 'E', ',', ';', '=', '<', '>', 'é', '\t'];
+
+        for_each_seed(0..3000, |rng| {
+            let text = (0..rng.below(40)).map(|_| alphabet[rng.below(alphabet.len())]).collect::<String>();
+            let mut output = Vec::new();
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+
+            let _ = interpreter.add(&text);
+            let _ = interpreter.add(&format!("10 {text}"));
+            let _ = interpreter.add(&format!("20 PRINT {text}"));
+            let _ = interpreter.add(&format!("30 IF {text}"));
+            let _ = interpreter.load(&text);
+            let _ = interpreter.eval_value(&text);
+            let _ = interpreter.restore(&text);
+            let _ = interpreter.restore(&format!("{SNAPSHOT_HEADER}\n{text}"));
+        });
+    }
+
+    #[test]
+    fn deeply_nested_lines_are_rejected() {
+        let lines = [
+            format!("10 PRINT {}1{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("10 PRINT {}1", "-".repeat(100_000)),
+            format!("10 PRINT 1{}", "+1".repeat(100_000)),
+            format!("10 IF {}1 = 1 THEN PRINT 1", "NOT ".repeat(100_000)),
+            format!("10 {}PRINT 1", "IF 1 = 1 THEN ".repeat(10_000)),
+        ];
+
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        for line in lines {
+            assert!(matches!(interpreter.add(&line), Err(InterpreterError::SyntaxError { .. })));
+        }
+        assert!(interpreter.add(&format!("10 PRINT {}1", "-".repeat(MAX_LINE_TOKENS - 1))).is_ok());
+        assert!(interpreter.run().is_ok());
+    }
+
+    #[test]
+    fn comments_are_listed_as_written() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 REM  Counts (to 3)\n20 'no space\n30 ' a \"quote\n40 REM\n50 IF 1 = 1 THEN REM ok").unwrap();
+
+        assert_eq!(interpreter.list(), [
+            "10 REM  Counts (to 3)",
+            "20 'no space",
+            "30 ' a \"quote",
+            "40 REM",
+            "50 IF 1 = 1 THEN REM ok",
+        ]);
+        let listing = interpreter.list();
+        interpreter.load(&listing.join("\n")).unwrap();
+        assert_eq!(interpreter.list(), listing);
+        assert!(interpreter.run().is_ok());
+    }
+
+    #[test]
+    fn stop_can_be_continued() {
+        let mut output = Vec::new();
+        {
+            let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+            interpreter.load("10 LET A = 1\n20 STOP\n30 PRINT A\n40 STOP\n50 END\n60 PRINT 0").unwrap();
+
+            assert!(matches!(interpreter.cont(), Err(InterpreterError::CannotContinue)));
+            assert_eq!(interpreter.run().unwrap(), RunOutcome::Stopped { line_number: 20 });
+            interpreter.set_variable("A", Value::Int(5)).unwrap();
+            assert_eq!(interpreter.cont().unwrap(), RunOutcome::Stopped { line_number: 40 });
+            assert_eq!(interpreter.cont().unwrap(), RunOutcome::Finished);
+            assert!(matches!(interpreter.cont(), Err(InterpreterError::CannotContinue)));
+
+            assert!(matches!(interpreter.run_for(100), RunStatus::Stopped { line_number: 20 }));
+            assert!(matches!(interpreter.run_for(100), RunStatus::Stopped { line_number: 40 }));
+            assert!(matches!(interpreter.run_for(100), RunStatus::Finished));
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "5\n1\n");
+    }
+
+    #[test]
+    fn replay_finds_first_diverging_line() {
+        let program = "10 INPUT \"Name? \"; N$\n20 INPUT \"Age? \"; A\n30 PRINT \"Hi \" + N$\n40 PRINT A * 2";
+
+        let mut transcript = Vec::new();
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new("Ada\nold\n36\n".as_bytes(), &mut output);
+        interpreter.load(program).unwrap();
+        interpreter.run_recorded(&mut transcript).unwrap();
+        let transcript = String::from_utf8(transcript).unwrap();
+
+        assert_eq!(transcript, [
+            TRANSCRIPT_HEADER,
+            "PROMPT Name? ",
+            "IN Ada",
+            "PROMPT Age? ",
+            "IN old",
+            "OUT Not a number: old, try again",
+            "PROMPT Age? ",
+            "IN 36",
+            "OUT Hi Ada",
+            "OUT 72",
+            "",
+        ].join("\n"));
+        assert!(interpreter.replay(&transcript).is_ok());
+
+        interpreter.add("40 PRINT A * 3").unwrap();
+        let error = interpreter.replay(&transcript).unwrap_err();
+        assert_eq!(error.to_string(), "Transcript line 10 differs: expected \"OUT 72\", got \"OUT 108\"");
+
+        interpreter.add("25 INPUT B").unwrap();
+        assert!(matches!(
+            interpreter.replay(&transcript),
+            Err(InterpreterError::TranscriptMismatch { line: 9, actual: Some(ref actual), .. })
+                if actual == "ERROR Runtime error in line 25: No more input"
+        ));
+
+        assert!(matches!(interpreter.replay("10 PRINT 1"), Err(InterpreterError::InvalidTranscript { .. })));
+    }
+
+    #[test]
+    fn stop_is_recorded_without_an_error() {
+        let mut transcript = Vec::new();
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(std::io::empty(), &mut output);
+        interpreter.load("10 PRINT 1\n20 STOP\n30 PRINT 2").unwrap();
+
+        assert_eq!(interpreter.run_recorded(&mut transcript).unwrap(), RunOutcome::Stopped { line_number: 20 });
+        let transcript = String::from_utf8(transcript).unwrap();
+        assert_eq!(transcript, format!("{TRANSCRIPT_HEADER}\nOUT 1\nSTOP 20\n"));
+        assert!(interpreter.replay(&transcript).is_ok());
+
+        interpreter.remove(20);
+        assert!(matches!(
+            interpreter.replay(&transcript),
+            Err(InterpreterError::TranscriptMismatch { line: 3, .. })
+        ));
+    }
 }
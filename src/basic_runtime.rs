@@ -0,0 +1,355 @@
+//! How BASIC values, arrays and built-in functions behave. The interpreter uses this file as its
+//! `runtime` module, and `Interpreter::to_rust` copies it into every program it writes, so
+//! transpiled programs compute exactly what the interpreter does.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// The value of a BASIC variable. Names ending in `$` hold strings, all others hold numbers,
+/// which are integers until they're combined with a floating point value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl fmt::Display for Value {
+    /// Floats always keep their decimal point or exponent, so `3.0` doesn't print like `3`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int(number) => write!(f, "{number}"),
+            Self::Float(number) => write!(f, "{number:?}"),
+            Self::Str(string) => write!(f, "{string}"),
+        }
+    }
+}
+
+/// Parses a number the way INPUT and `eval_value` accept them: an integer if it fits in an
+/// `i64`, otherwise a finite float.
+pub(crate) fn parse_number(input: &str) -> Option<Value> {
+    if let Ok(number) = input.parse() {
+        return Some(Value::Int(number));
+    }
+
+    input.parse::<f64>().ok().filter(|n| n.is_finite()).map(Value::Float)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ValueType {
+    Number,
+    Str,
+}
+
+pub(crate) fn variable_type(name: &str) -> ValueType {
+    if name.ends_with('$') { ValueType::Str } else { ValueType::Number }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CompareOp {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl CompareOp {
+    pub(crate) fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Self::Greater        => ordering.is_gt(),
+            Self::GreaterOrEqual => ordering.is_ge(),
+            Self::Less           => ordering.is_lt(),
+            Self::LessOrEqual    => ordering.is_le(),
+            Self::Equal          => ordering.is_eq(),
+            Self::NotEqual       => ordering.is_ne(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Builtin {
+    Abs,
+    Mod,
+    Min,
+    Max,
+    Rnd,
+    Sqr,
+}
+
+#[derive(Debug)]
+pub(crate) enum EvalError {
+    UnknownVariable(String),
+    DivisionByZero,
+    Overflow,
+    TypeMismatch,
+    UnknownArray(String),
+    IndexOutOfBounds { name: String, indices: Vec<i64>, bounds: Vec<usize> },
+    InvalidBound { name: String, bound: i64 },
+    ArrayTooLarge(String),
+    ArgumentCount(String),
+    InvalidArgument(String),
+    Native { name: String, message: String },
+}
+
+fn join_numbers<T: fmt::Display>(numbers: &[T]) -> String {
+    numbers.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownVariable(name) => write!(f, "Unknown variable: {name}"),
+            Self::DivisionByZero        => write!(f, "Division by zero"),
+            Self::Overflow              => write!(f, "Arithmetic overflow"),
+            Self::TypeMismatch          => write!(f, "Type mismatch"),
+            Self::UnknownArray(name)    => write!(f, "Array {name} is not dimensioned"),
+            Self::IndexOutOfBounds { name, indices, bounds } => {
+                write!(f, "Index out of bounds: {name}({}), dimensioned as {name}({})",
+                    join_numbers(indices), join_numbers(bounds))
+            },
+            Self::InvalidBound { name, bound } => write!(f, "Invalid bound for array {name}: {bound}"),
+            Self::ArrayTooLarge(name)   => write!(f, "Array {name} is too large"),
+            Self::ArgumentCount(name)   => write!(f, "Wrong number of arguments for {name}"),
+            Self::InvalidArgument(name) => write!(f, "Invalid argument for {name}"),
+            Self::Native { name, message } => write!(f, "Error in {name}: {message}"),
+        }
+    }
+}
+
+impl Value {
+    pub(crate) fn value_type(&self) -> ValueType {
+        match self {
+            Self::Str(_) => ValueType::Str,
+            _ => ValueType::Number,
+        }
+    }
+
+    /// Only NaN and the infinities aren't finite, which no BASIC number can be.
+    pub(crate) fn is_finite(&self) -> bool {
+        !matches!(self, Self::Float(number) if !number.is_finite())
+    }
+
+    pub(crate) fn is_true(&self) -> bool {
+        match self {
+            Self::Int(number) => *number != 0,
+            Self::Float(number) => *number != 0.0,
+            Self::Str(string) => !string.is_empty(),
+        }
+    }
+
+    pub(crate) fn as_float(&self) -> Result<f64, EvalError> {
+        match self {
+            Self::Int(number) => Ok(*number as f64),
+            Self::Float(number) => Ok(*number),
+            Self::Str(_) => Err(EvalError::TypeMismatch),
+        }
+    }
+
+    pub(crate) fn negate(self) -> Result<Value, EvalError> {
+        match self {
+            Self::Int(number) => number.checked_neg().map(Value::Int).ok_or(EvalError::Overflow),
+            Self::Float(number) => Ok(Value::Float(-number)),
+            Self::Str(_) => Err(EvalError::TypeMismatch),
+        }
+    }
+
+    /// Integer arithmetic stays integer, with `/` truncating. As soon as a float is involved the
+    /// whole operation is done in floating point, and a non-finite result counts as an error.
+    pub(crate) fn arithmetic(self, op: BinaryOp, right: Value) -> Result<Value, EvalError> {
+        match (op, self, right) {
+            (BinaryOp::Add, Self::Str(left), Self::Str(right)) => Ok(Self::Str(left + &right)),
+            (op, Self::Int(left), Self::Int(right)) => {
+                let result = match op {
+                    BinaryOp::Add => left.checked_add(right),
+                    BinaryOp::Sub => left.checked_sub(right),
+                    BinaryOp::Mul => left.checked_mul(right),
+                    BinaryOp::Div if right == 0 => return Err(EvalError::DivisionByZero),
+                    BinaryOp::Div => left.checked_div(right),
+                };
+                result.map(Self::Int).ok_or(EvalError::Overflow)
+            },
+            (op, left, right) => {
+                let (left, right) = (left.as_float()?, right.as_float()?);
+                let result = match op {
+                    BinaryOp::Add => left + right,
+                    BinaryOp::Sub => left - right,
+                    BinaryOp::Mul => left * right,
+                    BinaryOp::Div if right == 0.0 => return Err(EvalError::DivisionByZero),
+                    BinaryOp::Div => left / right,
+                };
+
+                if result.is_finite() {
+                    Ok(Self::Float(result))
+                } else {
+                    Err(EvalError::Overflow)
+                }
+            },
+        }
+    }
+
+    pub(crate) fn compare(&self, right: &Value) -> Result<Ordering, EvalError> {
+        match (self, right) {
+            (Self::Int(left), Self::Int(right)) => Ok(left.cmp(right)),
+            (Self::Str(left), Self::Str(right)) => Ok(left.cmp(right)),
+            (left, right) => {
+                left.as_float()?.partial_cmp(&right.as_float()?).ok_or(EvalError::TypeMismatch)
+            },
+        }
+    }
+
+    /// Array indices and bounds. Floats are truncated towards zero.
+    pub(crate) fn as_index(&self) -> Result<i64, EvalError> {
+        match self {
+            Self::Int(number) => Ok(*number),
+            Self::Float(number) => Ok(*number as i64),
+            Self::Str(_) => Err(EvalError::TypeMismatch),
+        }
+    }
+}
+
+/// Arrays can't have more elements than this, so that a single DIM can't exhaust memory.
+const MAX_ARRAY_SIZE: usize = 1 << 20;
+
+/// A DIM-ed array, stored in row-major order. Each index runs from 0 to its bound inclusive.
+#[derive(Debug)]
+pub(crate) struct Array {
+    pub(crate) bounds: Vec<usize>,
+    pub(crate) values: Vec<Value>,
+}
+
+impl Array {
+    /// The array `DIM name(bounds)` creates, filled with zeros or empty strings.
+    pub(crate) fn new(name: &str, bounds: Vec<i64>) -> Result<Self, EvalError> {
+        let bounds = bounds.into_iter().
+            map(|bound| {
+                usize::try_from(bound).map_err(|_| EvalError::InvalidBound { name: name.to_owned(), bound })
+            }).
+            collect::<Result<Vec<_>, _>>()?;
+        let size = bounds.iter().
+            try_fold(1usize, |size, bound| size.checked_mul(bound.checked_add(1)?)).
+            filter(|size| *size <= MAX_ARRAY_SIZE).
+            ok_or_else(|| EvalError::ArrayTooLarge(name.to_owned()))?;
+
+        let empty = match variable_type(name) {
+            ValueType::Number => Value::Int(0),
+            ValueType::Str => Value::Str(String::new()),
+        };
+
+        Ok(Array { bounds, values: vec![empty; size] })
+    }
+
+    /// The position of an element in `values`.
+    pub(crate) fn position(&self, name: &str, indices: &[i64]) -> Result<usize, EvalError> {
+        let out_of_bounds = || EvalError::IndexOutOfBounds {
+            name: name.to_owned(),
+            indices: indices.to_vec(),
+            bounds: self.bounds.clone(),
+        };
+
+        if indices.len() != self.bounds.len() {
+            return Err(out_of_bounds());
+        }
+
+        let mut position = 0;
+        for (index, bound) in indices.iter().zip(&self.bounds) {
+            let index = usize::try_from(*index).
+                ok().
+                filter(|index| index <= bound).
+                ok_or_else(out_of_bounds)?;
+            position = position * (bound + 1) + index;
+        }
+
+        Ok(position)
+    }
+}
+
+/// The next number from a splitmix64 generator, which is fine with any seed including 0.
+fn next_random(random_state: &Cell<u64>) -> u64 {
+    let state = random_state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+    random_state.set(state);
+
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub(crate) fn call_builtin(
+    name: &str,
+    builtin: Builtin,
+    arguments: &[Value],
+    random_state: &Cell<u64>,
+) -> Result<Value, EvalError> {
+    let argument_count = match builtin {
+        Builtin::Abs | Builtin::Rnd | Builtin::Sqr => 1..=1,
+        Builtin::Mod => 2..=2,
+        Builtin::Min | Builtin::Max => 1..=usize::MAX,
+    };
+    if !argument_count.contains(&arguments.len()) {
+        return Err(EvalError::ArgumentCount(name.to_owned()));
+    }
+    if arguments.iter().any(|argument| argument.value_type() != ValueType::Number) {
+        return Err(EvalError::TypeMismatch);
+    }
+
+    match builtin {
+        Builtin::Abs => {
+            match &arguments[0] {
+                Value::Int(number) => number.checked_abs().map(Value::Int).ok_or(EvalError::Overflow),
+                number => Ok(Value::Float(number.as_float()?.abs())),
+            }
+        },
+        Builtin::Mod => {
+            match (&arguments[0], &arguments[1]) {
+                (Value::Int(_), Value::Int(0)) => Err(EvalError::DivisionByZero),
+                (Value::Int(left), Value::Int(right)) => {
+                    left.checked_rem(*right).map(Value::Int).ok_or(EvalError::Overflow)
+                },
+                (left, right) => {
+                    let right = right.as_float()?;
+                    if right == 0.0 {
+                        return Err(EvalError::DivisionByZero);
+                    }
+                    Ok(Value::Float(left.as_float()? % right))
+                },
+            }
+        },
+        Builtin::Min | Builtin::Max => {
+            let mut best = &arguments[0];
+            for argument in &arguments[1..] {
+                let ordering = argument.compare(best)?;
+                if (matches!(builtin, Builtin::Min) && ordering.is_lt()) ||
+                    (matches!(builtin, Builtin::Max) && ordering.is_gt()) {
+                    best = argument;
+                }
+            }
+            Ok(best.clone())
+        },
+        Builtin::Rnd => {
+            // RND(N) is a whole number from 1 to N.
+            let limit = u64::try_from(arguments[0].as_index()?).
+                ok().
+                filter(|limit| *limit > 0).
+                ok_or_else(|| EvalError::InvalidArgument(name.to_owned()))?;
+            Ok(Value::Int((next_random(random_state) % limit) as i64 + 1))
+        },
+        Builtin::Sqr => {
+            let number = arguments[0].as_float()?;
+            if number < 0.0 {
+                return Err(EvalError::InvalidArgument(name.to_owned()));
+            }
+            Ok(Value::Float(number.sqrt()))
+        },
+    }
+}
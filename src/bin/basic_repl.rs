@@ -0,0 +1,211 @@
+#[path = "../basic.rs"]
+#[allow(dead_code)]
+mod basic;
+
+use basic::{Interpreter, InterpreterError, RunOutcome};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+
+/// Hands stdin to the interpreter one line at a time, so that READ never buffers ahead into the
+/// commands typed after RUN.
+struct StdinLines;
+
+impl Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+
+        let available = stdin.fill_buf()?;
+        let line_len = available.iter().
+            position(|b| *b == b'\n').
+            map_or(available.len(), |i| i + 1).
+            min(buf.len());
+
+        buf[..line_len].copy_from_slice(&available[..line_len]);
+        stdin.consume(line_len);
+        Ok(line_len)
+    }
+}
+
+fn main() {
+    let mut stdout = io::stdout();
+    let mut interpreter = Interpreter::new(StdinLines, &mut stdout);
+
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {},
+            Err(e) => {
+                println!("I/O error: {e}");
+                break;
+            },
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with(|c: char| c.is_ascii_digit()) {
+            enter_line(&mut interpreter, line);
+            continue;
+        }
+
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+
+        match command.to_uppercase().as_str() {
+            "LIST" => {
+                for code in interpreter.list() {
+                    println!("{code}");
+                }
+            },
+            "RUN" => report_outcome(interpreter.run()),
+            "CONT" => report_outcome(interpreter.cont()),
+            "NEW" => interpreter.clear(),
+            "DELETE" => {
+                match parse_line_range(argument) {
+                    Some((first, last)) => {
+                        if interpreter.remove_range(first..=last) == 0 {
+                            println!("No such line: {argument}");
+                        }
+                    },
+                    None => println!("Usage: DELETE <line>[-<line>]"),
+                }
+            },
+            "RENUM" => {
+                let mut numbers = argument.split(',').map(str::trim).filter(|n| !n.is_empty());
+                let start = numbers.next().map_or(Ok(10), str::parse);
+                let step = numbers.next().map_or(Ok(10), str::parse);
+
+                match (start, step, numbers.next()) {
+                    (Ok(start), Ok(step), None) => {
+                        if let Err(e) = interpreter.renumber(start, step) {
+                            println!("{e}");
+                        }
+                    },
+                    _ => println!("Usage: RENUM [<start>[, <step>]]"),
+                }
+            },
+            "SAVE" if !argument.is_empty() => {
+                let mut program = interpreter.list().join("\n");
+                program.push('\n');
+
+                if let Err(e) = fs::write(argument, program) {
+                    println!("Couldn't save {argument}: {e}");
+                }
+            },
+            "LOAD" if !argument.is_empty() => {
+                match fs::read_to_string(argument) {
+                    Ok(program) => {
+                        if let Err(errors) = interpreter.load(&program) {
+                            for error in errors {
+                                println!("{}", error.render());
+                            }
+                        }
+                    },
+                    Err(e) => println!("Couldn't load {argument}: {e}"),
+                }
+            },
+            "RECORD" if !argument.is_empty() => {
+                match fs::File::create(argument) {
+                    Ok(mut file) => report_outcome(interpreter.run_recorded(&mut file)),
+                    Err(e) => println!("Couldn't create {argument}: {e}"),
+                }
+            },
+            "REPLAY" if !argument.is_empty() => {
+                match fs::read_to_string(argument) {
+                    Ok(transcript) => {
+                        match interpreter.replay(&transcript) {
+                            Ok(()) => println!("Output matches {argument}"),
+                            Err(e) => println!("{e}"),
+                        }
+                    },
+                    Err(e) => println!("Couldn't read {argument}: {e}"),
+                }
+            },
+            "SAVE" | "LOAD" | "RECORD" | "REPLAY" => println!("Usage: {} <file>", command.to_uppercase()),
+            "QUIT" | "EXIT" => break,
+            _ => println!("Unknown command: {command}"),
+        }
+    }
+}
+
+/// Adds a numbered line to the program. A line number on its own deletes that line.
+fn enter_line<R: Read, W: Write>(interpreter: &mut Interpreter<R, W>, code: &str) {
+    if let Ok(line_number) = code.parse() {
+        interpreter.remove(line_number);
+        return;
+    }
+
+    if let Err(e) = interpreter.add(code) {
+        println!("{e}");
+    }
+}
+
+/// Prints where the program stopped, or why it failed.
+fn report_outcome(result: Result<RunOutcome, InterpreterError>) {
+    match result {
+        Ok(RunOutcome::Finished) => {},
+        Ok(RunOutcome::Stopped { line_number }) => println!("Stopped in line {line_number}"),
+        Err(e) => println!("{e}"),
+    }
+}
+
+/// Parses `30` or `30-50`.
+fn parse_line_range(argument: &str) -> Option<(u16, u16)> {
+    match argument.split_once('-') {
+        Some((first, last)) => Some((first.trim().parse().ok()?, last.trim().parse().ok()?)),
+        None => argument.parse().ok().map(|line_number| (line_number, line_number)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_ranges_are_parsed() {
+        assert_eq!(parse_line_range("30"), Some((30, 30)));
+        assert_eq!(parse_line_range("30-50"), Some((30, 50)));
+        assert_eq!(parse_line_range("30 - 50"), Some((30, 50)));
+    }
+
+    #[test]
+    fn bad_line_ranges_are_rejected() {
+        for argument in ["", "X", "30-", "-50", "30-50-70", "30-X", "70000", "-1"] {
+            assert_eq!(parse_line_range(argument), None, "{argument}");
+        }
+    }
+
+    #[test]
+    fn delete_removes_a_range_of_lines() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(io::empty(), &mut output);
+        interpreter.load("10 PRINT 1\n20 PRINT 2\n30 PRINT 3\n40 PRINT 4").unwrap();
+
+        let (first, last) = parse_line_range("15-30").unwrap();
+        assert_eq!(interpreter.remove_range(first..=last), 2);
+        assert_eq!(interpreter.list(), ["10 PRINT 1", "40 PRINT 4"]);
+
+        let (first, last) = parse_line_range("20").unwrap();
+        assert_eq!(interpreter.remove_range(first..=last), 0);
+    }
+
+    #[test]
+    fn a_bare_line_number_deletes_the_line() {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(io::empty(), &mut output);
+        interpreter.load("10 PRINT 1\n20 PRINT 2").unwrap();
+
+        enter_line(&mut interpreter, "20");
+        assert_eq!(interpreter.list(), ["10 PRINT 1"]);
+        enter_line(&mut interpreter, "30");
+        enter_line(&mut interpreter, "30 PRINT 3");
+        assert_eq!(interpreter.list(), ["10 PRINT 1", "30 PRINT 3"]);
+    }
+}
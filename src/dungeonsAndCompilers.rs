@@ -1,7 +1,9 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::io::{self, BufRead};
 use std::str::FromStr;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum Errors {
     DuplicateRoom(String),
@@ -11,6 +13,7 @@ pub enum Errors {
     DirectionParseError(String),
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Direction {
     North,
@@ -33,6 +36,7 @@ impl FromStr for Direction {
     }
 }
 
+#[allow(dead_code)]
 impl Direction {
     fn opposite(&self) -> Self {
         match self {
@@ -44,22 +48,34 @@ impl Direction {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Default)]
 pub struct Room {
     pub name: String,
-    links: HashMap<Direction, String>,
+    pub position: Option<(i32, i32)>,
+    links: HashMap<Direction, Link>,
 }
 
+#[allow(dead_code)]
 impl Room {
     fn new(name: &str) -> Self {
         Room { name: name.to_owned(), ..Self::default() }
     }
 }
 
+/// A corridor to a neighbouring room together with the cost of walking it.
+#[allow(dead_code)]
+struct Link {
+    room_name: String,
+    cost: u32,
+}
+
+#[allow(dead_code)]
 pub struct Dungeon {
     rooms: HashMap<String, Room>,
 }
 
+#[allow(dead_code)]
 impl Dungeon {
     pub fn new() -> Self {
         Dungeon { rooms: HashMap::new() }
@@ -70,42 +86,56 @@ impl Dungeon {
         let mut iterator = reader.lines().enumerate();
         let mut line_number = 0;
 
-        let (_, rooms_line) = iterator.next().ok_or_else(|| Errors::LineParseError { line_number: 0 })?;
+        let (_, rooms_line) = iterator.next().ok_or(Errors::LineParseError { line_number: 0 })?;
         let rooms_line = rooms_line.map_err(Errors::IoError)?;
         if rooms_line.trim() != "## Rooms" {
             return Err(Errors::LineParseError { line_number: 1 });
         }
 
-        while let Some((index, line)) = iterator.next() {
+        for (index, line) in iterator.by_ref() {
             line_number = index + 1;
             let line = line.map_err(Errors::IoError)?;
 
-            if line.trim().len() == 0 {
+            if line.trim().is_empty() {
                 break;
             }
 
-            let room_name = match_prefix("- ", &line).ok_or_else(|| Errors::LineParseError { line_number })?;
-            dungeon.add_room(room_name)?;
+            let room_description = match_prefix("- ", &line).ok_or(Errors::LineParseError { line_number })?;
+
+            // A suffix that isn't a position is part of the name, e.g. `- Hall (East)`.
+            let room_position = match_parenthesized_suffix(room_description).
+                and_then(|(room_name, position)| Some((room_name, parse_position(position)?)));
+
+            match room_position {
+                Some((room_name, position)) => dungeon.add_room_at(room_name, position)?,
+                None => dungeon.add_room(room_description)?,
+            }
         }
 
-        let (index, links_line) = iterator.next().ok_or_else(|| Errors::LineParseError { line_number })?;
+        let (index, links_line) = iterator.next().ok_or(Errors::LineParseError { line_number })?;
         let links_line = links_line.map_err(Errors::IoError)?;
         if links_line.trim() != "## Links" {
             return Err(Errors::LineParseError { line_number: index + 1 });
         }
 
-        while let Some((index, line)) = iterator.next() {
+        for (index, line) in iterator {
             let line_number = index + 1;
             let line = line.map_err(Errors::IoError)?;
             let link_description = match_prefix("- ", &line).
-                ok_or_else(|| Errors::LineParseError { line_number })?;
+                ok_or(Errors::LineParseError { line_number })?;
 
             let parts: Vec<&str> = link_description.split(" -> ").collect();
             if parts.len() != 3 {
                 return Err(Errors::LineParseError { line_number });
             }
 
-            dungeon.set_link(parts[0], parts[1].parse()?, parts[2])?;
+            // Likewise, a suffix is only a cost if it's a number and not part of a room's name.
+            let (other_room_name, cost) = match_parenthesized_suffix(parts[2]).
+                filter(|_| !dungeon.rooms.contains_key(parts[2])).
+                and_then(|(other_room_name, cost)| Some((other_room_name, cost.trim().parse().ok()?))).
+                unwrap_or((parts[2], 1));
+
+            dungeon.set_weighted_link(parts[0], parts[1].parse()?, other_room_name, cost)?;
         }
 
         Ok(dungeon)
@@ -120,19 +150,41 @@ impl Dungeon {
         Ok(())
     }
 
+    /// Like `add_room`, but places the room at the given grid coordinates, which
+    /// `find_cheapest_path` can use to guide its search.
+    pub fn add_room_at(&mut self, name: &str, position: (i32, i32)) -> Result<(), Errors> {
+        self.add_room(name)?;
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.position = Some(position);
+        }
+        Ok(())
+    }
+
     pub fn set_link(
         &mut self,
         room_name: &str,
         direction: Direction,
         other_room_name: &str,
+    ) -> Result<(), Errors> {
+        self.set_weighted_link(room_name, direction, other_room_name, 1)
+    }
+
+    /// Links two rooms both ways with a corridor that costs `cost` to walk in
+    /// either direction.
+    pub fn set_weighted_link(
+        &mut self,
+        room_name: &str,
+        direction: Direction,
+        other_room_name: &str,
+        cost: u32,
     ) -> Result<(), Errors> {
         let room = self.rooms.get_mut(room_name).
             ok_or_else(|| Errors::UnknownRoom(room_name.to_owned()))?;
-        room.links.insert(direction, other_room_name.to_owned());
+        room.links.insert(direction, Link { room_name: other_room_name.to_owned(), cost });
 
         let other_room = self.rooms.get_mut(other_room_name).
             ok_or_else(|| Errors::UnknownRoom(other_room_name.to_owned()))?;
-        other_room.links.insert(direction.opposite(), room_name.to_owned());
+        other_room.links.insert(direction.opposite(), Link { room_name: room_name.to_owned(), cost });
 
         Ok(())
     }
@@ -146,9 +198,9 @@ impl Dungeon {
         let room = self.rooms.get(room_name).
             ok_or_else(|| Errors::UnknownRoom(room_name.to_owned()))?;
 
-        if let Some(other_room_name) = room.links.get(&direction) {
-            self.rooms.get(other_room_name).
-                ok_or_else(|| Errors::UnknownRoom(other_room_name.to_owned())).
+        if let Some(link) = room.links.get(&direction) {
+            self.rooms.get(&link.room_name).
+                ok_or_else(|| Errors::UnknownRoom(link.room_name.to_owned())).
                 map(Some)
         } else {
             Ok(None)
@@ -191,7 +243,7 @@ impl Dungeon {
             }
         }
 
-        if parents.get(end_room.name.as_str()).is_none() {
+        if !parents.contains_key(end_room.name.as_str()) {
             return Ok(None);
         }
 
@@ -206,12 +258,220 @@ impl Dungeon {
         path.reverse();
         Ok(Some(path))
     }
+
+    /// Finds the path between two rooms with the lowest total link cost and
+    /// returns that cost together with the rooms along the way.
+    ///
+    /// When every room has a position and no link is cheaper than the Manhattan
+    /// distance it spans, the distance to the end room is used as an A* heuristic.
+    /// Otherwise this is a plain Dijkstra search.
+    pub fn find_cheapest_path(
+        &self,
+        start_room_name: &str,
+        end_room_name: &str
+    ) -> Result<Option<(u64, Vec<&Room>)>, Errors> {
+
+        let start_room = self.get_room(start_room_name)?;
+        let end_room = self.get_room(end_room_name)?;
+
+        let estimate: Box<dyn Fn(&Room) -> u64> = match (end_room.position, self.positions_are_admissible()) {
+            (Some(end_position), true) => Box::new(move |room: &Room| {
+                room.position.map_or(0, |position| manhattan_distance(position, end_position))
+            }),
+            _ => Box::new(|_: &Room| 0),
+        };
+
+        let mut room_heap = BinaryHeap::<Reverse<(u64, u64, &str)>>::new();
+        let mut costs = HashMap::<&str, u64>::new();
+        let mut parents = HashMap::<&str, &str>::new();
+        let mut done = HashSet::new();
+
+        costs.insert(&start_room.name, 0);
+        room_heap.push(Reverse((estimate(start_room), 0, start_room.name.as_str())));
+
+        while let Some(Reverse((_, cost, current_room_name))) = room_heap.pop() {
+            if current_room_name == end_room_name {
+                break;
+            }
+            if !done.insert(current_room_name) {
+                continue;
+            }
+
+            let current_room = self.get_room(current_room_name)?;
+            for link in current_room.links.values() {
+                let next_room = self.get_room(&link.room_name)?;
+                let next_cost = cost + u64::from(link.cost);
+
+                if costs.get(next_room.name.as_str()).is_none_or(|&known| next_cost < known) {
+                    costs.insert(&next_room.name, next_cost);
+                    parents.insert(&next_room.name, &current_room.name);
+                    room_heap.push(Reverse((next_cost + estimate(next_room), next_cost, next_room.name.as_str())));
+                }
+            }
+        }
+
+        let total_cost = match costs.get(end_room.name.as_str()) {
+            Some(&total_cost) => total_cost,
+            None => return Ok(None),
+        };
+
+        let mut path = vec![end_room];
+        let mut current_room_name = end_room.name.as_str();
+
+        while let Some(parent_name) = parents.get(&current_room_name) {
+            path.push(self.get_room(parent_name)?);
+            current_room_name = parent_name;
+        }
+
+        path.reverse();
+        Ok(Some((total_cost, path)))
+    }
+
+    fn positions_are_admissible(&self) -> bool {
+        self.rooms.values().all(|room| {
+            let position = match room.position {
+                Some(position) => position,
+                None => return false,
+            };
+
+            room.links.values().all(|link| {
+                match self.rooms.get(&link.room_name).and_then(|other_room| other_room.position) {
+                    Some(other_position) => u64::from(link.cost) >= manhattan_distance(position, other_position),
+                    None => false,
+                }
+            })
+        })
+    }
 }
 
-pub fn match_prefix<'a, 'b>(prefix: &'a str, input: &'b str) -> Option<&'b str> {
-    if input.starts_with(prefix) {
-        Some(&input[prefix.len()..])
-    } else {
-        None
+#[allow(dead_code)]
+fn manhattan_distance((x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> u64 {
+    u64::from(x1.abs_diff(x2)) + u64::from(y1.abs_diff(y2))
+}
+
+/// Splits `"Name (details)"` into `"Name"` and `"details"`.
+#[allow(dead_code)]
+fn match_parenthesized_suffix(input: &str) -> Option<(&str, &str)> {
+    let input = input.strip_suffix(')')?;
+    let (head, details) = input.rsplit_once(" (")?;
+    Some((head, details))
+}
+
+#[allow(dead_code)]
+fn parse_position(input: &str) -> Option<(i32, i32)> {
+    let (x, y) = input.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+#[allow(dead_code)]
+pub fn match_prefix<'a>(prefix: &str, input: &'a str) -> Option<&'a str> {
+    input.strip_prefix(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_names(path: &[&Room]) -> Vec<String> {
+        path.iter().map(|room| room.name.clone()).collect()
+    }
+
+    #[test]
+    fn cheap_detour_beats_expensive_link() {
+        let mut dungeon = Dungeon::new();
+        for name in ["A", "B", "C", "D"] {
+            dungeon.add_room(name).unwrap();
+        }
+        dungeon.set_weighted_link("A", Direction::East, "B", 10).unwrap();
+        dungeon.set_link("A", Direction::North, "C").unwrap();
+        dungeon.set_weighted_link("C", Direction::East, "D", 2).unwrap();
+        dungeon.set_link("D", Direction::South, "B").unwrap();
+
+        let (cost, path) = dungeon.find_cheapest_path("A", "B").unwrap().unwrap();
+        assert_eq!(cost, 4);
+        assert_eq!(room_names(&path), ["A", "C", "D", "B"]);
+
+        let path = dungeon.find_path("A", "B").unwrap().unwrap();
+        assert_eq!(room_names(&path), ["A", "B"]);
+
+        let (cost, path) = dungeon.find_cheapest_path("B", "B").unwrap().unwrap();
+        assert_eq!((cost, room_names(&path)), (0, vec!["B".to_owned()]));
+    }
+
+    #[test]
+    fn link_costs_are_read_from_text() {
+        let text = "## Rooms\n- Entrance\n- Hall (East)\n- Vault\n\n## Links\n\
+            - Entrance -> East -> Hall (East)\n\
+            - Hall (East) -> North -> Vault (5)\n\
+            - Entrance -> North -> Vault (7)\n";
+        let dungeon = Dungeon::from_reader(text.as_bytes()).unwrap();
+
+        assert!(dungeon.get_room("Hall (East)").unwrap().position.is_none());
+        let (cost, path) = dungeon.find_cheapest_path("Entrance", "Vault").unwrap().unwrap();
+        assert_eq!((cost, room_names(&path)), (6, vec!["Entrance".to_owned(), "Hall (East)".to_owned(), "Vault".to_owned()]));
+
+        let text = "## Rooms\n- A\n- B (North)\n\n## Links\n- A -> North -> B (North)\n";
+        let dungeon = Dungeon::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(dungeon.find_cheapest_path("A", "B (North)").unwrap().unwrap().0, 1);
+
+        let text = "## Rooms\n- A\n- B\n\n## Links\n- A -> North -> B (x)\n";
+        assert!(matches!(Dungeon::from_reader(text.as_bytes()), Err(Errors::UnknownRoom(name)) if name == "B (x)"));
+    }
+
+    #[test]
+    fn positions_guide_the_search() {
+        let text = "## Rooms\n- A (0, 0)\n- B (1, 0)\n- C (0, 1)\n- D (1, 1)\n- E (2, 1)\n\n## Links\n\
+            - A -> East -> B (3)\n\
+            - A -> North -> C\n\
+            - C -> East -> D\n\
+            - B -> North -> D (2)\n\
+            - D -> East -> E (4)\n";
+        let dungeon = Dungeon::from_reader(text.as_bytes()).unwrap();
+
+        assert_eq!(dungeon.get_room("A").unwrap().position, Some((0, 0)));
+        assert!(dungeon.positions_are_admissible());
+
+        let (cost, path) = dungeon.find_cheapest_path("A", "E").unwrap().unwrap();
+        assert_eq!((cost, room_names(&path)), (6, vec!["A".to_owned(), "C".to_owned(), "D".to_owned(), "E".to_owned()]));
+    }
+
+    #[test]
+    fn shortcuts_cheaper_than_their_distance_disable_the_heuristic() {
+        // The direct link looks best to the heuristic, but the detour through the far away
+        // room T is cheaper.
+        let text = "## Rooms\n- S (0, 0)\n- G (2, 0)\n- T (0, 10)\n\n## Links\n\
+            - S -> East -> G (5)\n\
+            - S -> North -> T\n\
+            - T -> East -> G\n";
+        let dungeon = Dungeon::from_reader(text.as_bytes()).unwrap();
+
+        assert!(!dungeon.positions_are_admissible());
+        let (cost, path) = dungeon.find_cheapest_path("S", "G").unwrap().unwrap();
+        assert_eq!((cost, room_names(&path)), (2, vec!["S".to_owned(), "T".to_owned(), "G".to_owned()]));
+    }
+
+    #[test]
+    fn unreachable_rooms_have_no_path() {
+        let text = "## Rooms\n- A\n- B\n- C\n\n## Links\n- A -> East -> B (3)\n";
+        let dungeon = Dungeon::from_reader(text.as_bytes()).unwrap();
+
+        assert!(dungeon.find_cheapest_path("A", "C").unwrap().is_none());
+        assert!(matches!(dungeon.find_cheapest_path("A", "Z"), Err(Errors::UnknownRoom(_))));
+    }
+
+    #[test]
+    fn expensive_paths_dont_overflow() {
+        let mut dungeon = Dungeon::new();
+        for name in ["A", "B", "C", "D"] {
+            dungeon.add_room(name).unwrap();
+        }
+        dungeon.set_weighted_link("A", Direction::East, "B", u32::MAX).unwrap();
+        dungeon.set_weighted_link("B", Direction::East, "D", u32::MAX).unwrap();
+        dungeon.set_weighted_link("A", Direction::North, "C", u32::MAX).unwrap();
+        dungeon.set_weighted_link("C", Direction::East, "D", 1).unwrap();
+
+        let (cost, path) = dungeon.find_cheapest_path("A", "D").unwrap().unwrap();
+        assert_eq!(cost, u64::from(u32::MAX) + 1);
+        assert_eq!(room_names(&path), ["A", "C", "D"]);
     }
 }
@@ -4,6 +4,8 @@
 
 mod CSScolors;
 mod colorsChallange;
+#[allow(non_snake_case)]
+mod dungeonsAndCompilers;
 
 fn main() {
     println!("Hello, Rust!");